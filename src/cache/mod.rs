@@ -1,14 +1,35 @@
-use crate::page::Page;
+/*
+Buffers are protected at two levels, which serve different purposes
+- Pinning keeps the page resident. A pinned buffer is never chosen for replacement, but pinning
+  says nothing about who may read or write the page
+- Latching coordinates access to the page bytes. Any number of readers can hold the read latch,
+  or a single writer the write latch. Latches are only held for the duration of an access
 
-struct Buffer<'a> {
-    page: Option<&'a Page>,
+A caller pins first, latches around each access, and unpins once it no longer needs the page.
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::log::LogManager;
+use crate::page::{Page, PageError, PageManager};
+
+struct Buffer {
+    page: Option<Page>,
     page_position: usize,
     tx_id: i32,
-    lsn: i32,
+    lsn: u32,
     pins: usize,
+    dirty: bool,
+    // Set on every pin, cleared when the clock hand passes over the buffer
+    reference: bool,
+    // Pool tick at which the buffer was last unpinned, for picking the least recently used
+    last_used: u64,
+    latch: RwLock<()>,
 }
 
-impl<'a> Buffer<'a> {
+impl Buffer {
     pub fn new() -> Self {
         Self {
             page: None,
@@ -16,11 +37,24 @@ impl<'a> Buffer<'a> {
             tx_id: -1,
             lsn: 1,
             pins: 0,
+            dirty: false,
+            reference: false,
+            last_used: 0,
+            latch: RwLock::new(()),
         }
     }
 
+    pub fn page(&self) -> Option<&Page> {
+        self.page.as_ref()
+    }
+
+    pub fn page_mut(&mut self) -> Option<&mut Page> {
+        self.page.as_mut()
+    }
+
     pub fn pin(&mut self) {
         self.pins += 1;
+        self.reference = true;
     }
 
     pub fn unpin(&mut self) {
@@ -32,10 +66,573 @@ impl<'a> Buffer<'a> {
         self.pins > 0
     }
 
-    pub fn mark_modified(&mut self, tx_id: i32, lsn: i32) {
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // The latch guards no data of its own, so a panic while holding it leaves nothing broken
+    pub fn latch_read(&self) -> RwLockReadGuard<'_, ()> {
+        self.latch.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn latch_write(&self) -> RwLockWriteGuard<'_, ()> {
+        self.latch.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn mark_modified(&mut self, tx_id: i32, lsn: u32) {
+        self.dirty = true;
         self.tx_id = tx_id;
         if lsn > 0 {
             self.lsn = lsn;
         }
     }
 }
+
+#[derive(Debug)]
+pub enum BufferError {
+    NoAvailableFrame,
+    Page(PageError),
+}
+
+impl std::fmt::Display for BufferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BufferError::NoAvailableFrame => write!(f, "Every frame in the buffer pool is pinned"),
+            BufferError::Page(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BufferError::Page(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<PageError> for BufferError {
+    fn from(err: PageError) -> Self {
+        BufferError::Page(err)
+    }
+}
+
+impl From<io::Error> for BufferError {
+    fn from(err: io::Error) -> Self {
+        BufferError::Page(PageError::Io(err))
+    }
+}
+
+// A fixed set of frames holding pages of one PageManager. Pages stay in their frame until the
+// frame is needed for another page while unpinned, the least recently unpinned frame going
+// first. Dirty pages are written back at that point. With a log attached, a page is only
+// written once the log is on disk up to the last record that changed it (write-ahead logging)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplacementPolicy {
+    // Evicts the buffer unpinned longest ago
+    Lru,
+    // Second chance, evicts the first buffer the hand finds that wasn't pinned since its last pass
+    Clock,
+}
+
+struct ClockReplacer {
+    hand: usize,
+}
+
+impl ClockReplacer {
+    // After one full turn every unpinned buffer has its reference bit cleared, so two turns are
+    // enough to find a victim if there is one
+    fn victim(&mut self, buffers: &mut [Buffer]) -> Option<usize> {
+        for _ in 0..2 * buffers.len() {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % buffers.len();
+
+            let buffer = &mut buffers[frame];
+            if buffer.is_pinned() {
+                continue;
+            }
+            if buffer.reference {
+                buffer.reference = false;
+                continue;
+            }
+            return Some(frame);
+        }
+        None
+    }
+}
+
+pub struct BufferPool {
+    buffers: Vec<Buffer>,
+    pm: PageManager,
+    log: Option<LogManager>,
+    frames: HashMap<usize, usize>,
+    tick: u64,
+    // None when replacing by LRU
+    clock: Option<ClockReplacer>,
+}
+
+impl BufferPool {
+    pub fn new(pm: PageManager, n_frames: usize) -> Self {
+        Self::with_policy(pm, n_frames, ReplacementPolicy::Lru)
+    }
+
+    pub fn with_policy(pm: PageManager, n_frames: usize, policy: ReplacementPolicy) -> Self {
+        Self {
+            buffers: (0..n_frames).map(|_| Buffer::new()).collect(),
+            pm,
+            log: None,
+            frames: HashMap::new(),
+            tick: 0,
+            clock: match policy {
+                ReplacementPolicy::Lru => None,
+                ReplacementPolicy::Clock => Some(ClockReplacer { hand: 0 }),
+            },
+        }
+    }
+
+    pub fn with_log(pm: PageManager, log: LogManager, n_frames: usize) -> Self {
+        Self {
+            log: Some(log),
+            ..Self::new(pm, n_frames)
+        }
+    }
+
+    pub fn log(&mut self) -> Option<&mut LogManager> {
+        self.log.as_mut()
+    }
+
+    // Returns the frame holding the page at position, reading it in if it isn't resident
+    pub fn pin(&mut self, position: usize) -> Result<usize, BufferError> {
+        if let Some(&frame) = self.frames.get(&position) {
+            self.buffers[frame].pin();
+            return Ok(frame);
+        }
+
+        // Empty frames go first so nothing is evicted while there is room
+        let frame = self
+            .buffers
+            .iter()
+            .position(|buffer| buffer.page.is_none())
+            .or_else(|| self.victim())
+            .ok_or(BufferError::NoAvailableFrame)?;
+        self.evict(frame)?;
+
+        let page = self.pm.read_page(position)?;
+        let buffer = &mut self.buffers[frame];
+        buffer.page = Some(page);
+        buffer.page_position = position;
+        buffer.pin();
+        self.frames.insert(position, frame);
+        Ok(frame)
+    }
+
+    fn victim(&mut self) -> Option<usize> {
+        if let Some(clock) = self.clock.as_mut() {
+            return clock.victim(&mut self.buffers);
+        }
+        (0..self.buffers.len())
+            .filter(|&frame| !self.buffers[frame].is_pinned())
+            .min_by_key(|&frame| self.buffers[frame].last_used)
+    }
+
+    // Callers should only touch frames they hold a pin on, and pass dirty on unpin after
+    // changing the page
+    pub fn page(&self, frame: usize) -> Option<&Page> {
+        self.buffers[frame].page()
+    }
+
+    pub fn page_mut(&mut self, frame: usize) -> Option<&mut Page> {
+        self.buffers[frame].page_mut()
+    }
+
+    // Latches for the page in frame, to be taken around each access once it is pinned
+    pub fn latch_read(&self, frame: usize) -> RwLockReadGuard<'_, ()> {
+        self.buffers[frame].latch_read()
+    }
+
+    pub fn latch_write(&self, frame: usize) -> RwLockWriteGuard<'_, ()> {
+        self.buffers[frame].latch_write()
+    }
+
+    // Records that tx_id changed the page in frame, described by the log record at lsn
+    pub fn mark_modified(&mut self, frame: usize, tx_id: i32, lsn: u32) {
+        self.buffers[frame].mark_modified(tx_id, lsn);
+    }
+
+    pub fn unpin(&mut self, frame: usize, dirty: bool) {
+        let buffer = &mut self.buffers[frame];
+        buffer.dirty |= dirty;
+        buffer.unpin();
+        if !buffer.is_pinned() {
+            self.tick += 1;
+            buffer.last_used = self.tick;
+        }
+    }
+
+    // Writes the page in frame back if it is dirty, flushing the log first
+    pub fn flush_frame(&mut self, frame: usize) -> Result<(), BufferError> {
+        let buffer = &mut self.buffers[frame];
+        let Some(page) = buffer.page.as_ref() else {
+            return Ok(());
+        };
+        if !buffer.is_dirty() {
+            return Ok(());
+        }
+        if let Some(log) = self.log.as_mut() {
+            log.flush_since_lsn(buffer.lsn)?;
+        }
+        self.pm.write_page(buffer.page_position, page)?;
+        buffer.dirty = false;
+        Ok(())
+    }
+
+    // Pinned frames are written too, their pages just stay resident
+    pub fn flush_all(&mut self) -> Result<(), BufferError> {
+        for frame in 0..self.buffers.len() {
+            self.flush_frame(frame)?;
+        }
+        self.pm.flush()?;
+        Ok(())
+    }
+
+    // Writes back the frames last changed by tx_id and syncs them, so what it wrote no longer
+    // depends on the pool or the OS. Frames changed by another transaction since are left to it
+    pub fn flush_tx(&mut self, tx_id: i32) -> Result<(), BufferError> {
+        for frame in 0..self.buffers.len() {
+            if self.buffers[frame].tx_id == tx_id {
+                self.flush_frame(frame)?;
+            }
+        }
+        self.pm.sync_data()?;
+        Ok(())
+    }
+
+    // Flushes every dirty frame and then writes a checkpoint listing active_txs to the log.
+    // Transactions write through a mutable borrow of the pool, so none can change a page while
+    // this runs
+    pub fn checkpoint(&mut self, active_txs: &[i32]) -> Result<u32, BufferError> {
+        self.flush_all()?;
+        let log = self
+            .log
+            .as_mut()
+            .expect("Checkpoints need a buffer pool with a log");
+        Ok(log.checkpoint(active_txs)?)
+    }
+
+    fn evict(&mut self, frame: usize) -> Result<(), BufferError> {
+        self.flush_frame(frame)?;
+        let buffer = &mut self.buffers[frame];
+        if buffer.page.take().is_none() {
+            return Ok(());
+        }
+        self.frames.remove(&buffer.page_position);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Barrier;
+    use std::thread;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 16;
+
+    fn page_manager(path: &std::path::Path, n_pages: usize) -> PageManager {
+        let mut pm = PageManager::new(path.to_str().unwrap(), PAGESIZE).unwrap();
+        for i in 0..n_pages {
+            pm.append_page(&Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+        pm
+    }
+
+    #[test]
+    fn pool_runs_out_of_frames() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 4);
+        let mut pool = BufferPool::new(pm, 3);
+
+        let frames: Vec<usize> = (0..3).map(|i| pool.pin(i).unwrap()).collect();
+        assert_eq!(frames, vec![0, 1, 2]);
+        assert!(matches!(pool.pin(3), Err(BufferError::NoAvailableFrame)));
+
+        // A resident page doesn't need a free frame
+        assert_eq!(pool.pin(1).unwrap(), 1);
+        pool.unpin(1, false);
+        assert!(matches!(pool.pin(3), Err(BufferError::NoAvailableFrame)));
+
+        pool.unpin(1, false);
+        assert_eq!(pool.pin(3).unwrap(), 1);
+        assert_eq!(
+            pool.buffers[1].page.as_ref().unwrap().read(),
+            &[3; PAGESIZE]
+        );
+        assert!(!pool.frames.contains_key(&1));
+    }
+
+    #[test]
+    fn pool_evicts_least_recently_unpinned() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 5);
+        let mut pool = BufferPool::new(pm, 3);
+
+        for i in 0..3 {
+            pool.pin(i).unwrap();
+        }
+        // Page 1 is unpinned first, but pinned and released again after the others
+        for i in [1, 2, 0] {
+            pool.unpin(i, false);
+        }
+        pool.pin(1).unwrap();
+        pool.unpin(1, false);
+
+        assert_eq!(pool.pin(3).unwrap(), 2);
+        assert!(!pool.frames.contains_key(&2));
+        assert_eq!(pool.pin(4).unwrap(), 0);
+        assert!(!pool.frames.contains_key(&0));
+        assert_eq!(pool.frames.get(&1), Some(&1));
+    }
+
+    #[test]
+    fn clock_gives_second_chance() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 6);
+        let mut pool = BufferPool::with_policy(pm, 3, ReplacementPolicy::Clock);
+
+        for i in 0..3 {
+            let frame = pool.pin(i).unwrap();
+            pool.unpin(frame, false);
+        }
+        // Everything was referenced, so the hand clears a full turn and comes back to frame 0
+        assert_eq!(pool.pin(3).unwrap(), 0);
+        pool.unpin(0, false);
+
+        // Page 1 is referenced again before the hand reaches it, so page 2 goes instead
+        let frame = pool.pin(1).unwrap();
+        pool.unpin(frame, false);
+        assert_eq!(pool.pin(4).unwrap(), 2);
+        assert_eq!(pool.frames.get(&1), Some(&1));
+        assert!(!pool.frames.contains_key(&2));
+
+        // Its bit was cleared on the way past, so the next sweep takes it
+        pool.unpin(2, false);
+        assert_eq!(pool.pin(5).unwrap(), 1);
+    }
+
+    #[test]
+    fn clock_skips_pinned() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 3);
+        let mut pool = BufferPool::with_policy(pm, 2, ReplacementPolicy::Clock);
+
+        pool.pin(0).unwrap();
+        let frame = pool.pin(1).unwrap();
+        assert!(matches!(pool.pin(2), Err(BufferError::NoAvailableFrame)));
+        pool.unpin(frame, false);
+        assert_eq!(pool.pin(2).unwrap(), frame);
+    }
+
+    #[test]
+    fn pool_writes_back_dirty_pages() {
+        let dir = tempdir().unwrap();
+        let mut pm = page_manager(&dir.path().join("testfile.bin"), 3);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let hook_writes = Rc::clone(&writes);
+        pm.set_write_hook(move |position, _| hook_writes.borrow_mut().push(position));
+        let mut pool = BufferPool::new(pm, 1);
+
+        let frame = pool.pin(0).unwrap();
+        pool.unpin(frame, false);
+        let frame = pool.pin(1).unwrap();
+        pool.unpin(frame, true);
+        pool.pin(2).unwrap();
+        assert_eq!(*writes.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn buffer_owns_page() {
+        let mut buffer = Buffer::new();
+        assert!(buffer.page().is_none());
+
+        buffer.page = Some(Page::new(PAGESIZE));
+        buffer.page_mut().unwrap().set_i32(0, 42);
+        buffer.mark_modified(1, 5);
+        assert_eq!(buffer.page().unwrap().get_i32(0), 42);
+        assert_eq!(buffer.lsn, 5);
+    }
+
+    #[test]
+    fn buffer_dirty_only_after_modification() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 2);
+        let mut pool = BufferPool::new(pm, 2);
+
+        let frame = pool.pin(0).unwrap();
+        assert!(!pool.buffers[frame].is_dirty());
+        pool.page_mut(frame).unwrap().mutate()[0] = 7;
+        assert!(!pool.buffers[frame].is_dirty());
+
+        pool.mark_modified(frame, 1, 0);
+        assert!(pool.buffers[frame].is_dirty());
+        pool.flush_frame(frame).unwrap();
+        assert!(!pool.buffers[frame].is_dirty());
+
+        let frame = pool.pin(1).unwrap();
+        pool.unpin(frame, false);
+        assert!(!pool.buffers[frame].is_dirty());
+    }
+
+    #[test]
+    fn pool_mutated_page_survives_eviction() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 2);
+        let mut pool = BufferPool::new(pm, 1);
+
+        let frame = pool.pin(0).unwrap();
+        pool.page_mut(frame).unwrap().mutate()[..3].copy_from_slice(b"new");
+        pool.unpin(frame, true);
+
+        let frame = pool.pin(1).unwrap();
+        assert_eq!(pool.page(frame).unwrap().read(), &[1; PAGESIZE]);
+        pool.unpin(frame, false);
+
+        let frame = pool.pin(0).unwrap();
+        assert_eq!(&pool.page(frame).unwrap().read()[..4], b"new\0");
+    }
+
+    #[test]
+    fn pool_flush_all() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let pm = page_manager(&file_path, 4);
+        let mut pool = BufferPool::new(pm, 4);
+
+        for i in 0..3 {
+            let frame = pool.pin(i).unwrap();
+            pool.page_mut(frame).unwrap().mutate()[0] = 10 + i as u8;
+            // The last one stays pinned
+            if i < 2 {
+                pool.unpin(frame, true);
+            } else {
+                pool.mark_modified(frame, 1, 0);
+            }
+        }
+        pool.pin(3).unwrap();
+
+        pool.flush_all().unwrap();
+        assert!(pool.buffers.iter().all(|buffer| !buffer.dirty));
+        assert!(pool.buffers[2].is_pinned());
+        drop(pool);
+
+        let mut pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        for i in 0..3 {
+            let mut expected = [i as u8; PAGESIZE];
+            expected[0] = 10 + i as u8;
+            assert_eq!(pm.read_page(i).unwrap().read(), &expected);
+        }
+        assert_eq!(pm.read_page(3).unwrap().read(), &[3; PAGESIZE]);
+    }
+
+    #[test]
+    fn pool_flushes_log_before_page() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 2);
+        let log_path = dir.path().join("logfile.bin");
+        let log = LogManager::new(log_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut pool = BufferPool::with_log(pm, log, 1);
+
+        let logged_first = Rc::new(RefCell::new(Vec::new()));
+        let hook_logged = Rc::clone(&logged_first);
+        pool.pm.set_write_hook(move |position, _| {
+            let log = std::fs::read(&log_path).unwrap();
+            let logged = log.windows(6).any(|window| window == b"update");
+            hook_logged.borrow_mut().push((position, logged));
+        });
+
+        let frame = pool.pin(0).unwrap();
+        let log = pool.log().unwrap();
+        log.append(b"older").unwrap();
+        let lsn = log.append(b"update").unwrap();
+        pool.page_mut(frame).unwrap().set_i32(0, 7);
+        pool.mark_modified(frame, 1, lsn);
+        pool.unpin(frame, false);
+
+        pool.pin(1).unwrap();
+        assert_eq!(*logged_first.borrow(), vec![(0, true)]);
+        assert!(pool.log().unwrap().metrics().flushed_lsn >= lsn);
+    }
+
+    #[test]
+    fn pool_pin_past_end() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 1);
+        let mut pool = BufferPool::new(pm, 2);
+
+        let err = pool.pin(1).unwrap_err();
+        assert!(matches!(err, BufferError::Page(PageError::Io(_))));
+        assert_eq!(pool.pin(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn shared_read_latches() {
+        let mut buffer = Buffer::new();
+        buffer.pin();
+        let barrier = Barrier::new(2);
+
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let _guard = buffer.latch_read();
+                    // Both readers must hold the latch at the same time to get past this
+                    barrier.wait();
+                    assert!(buffer.latch.try_write().is_err());
+                    barrier.wait();
+                });
+            }
+        });
+        assert!(buffer.is_pinned());
+    }
+
+    #[test]
+    fn write_latch_excludes_readers() {
+        let buffer = Buffer::new();
+        let guard = buffer.latch_write();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert!(buffer.latch.try_read().is_err());
+                assert!(buffer.latch.try_write().is_err());
+            });
+        });
+
+        drop(guard);
+        assert!(buffer.latch.try_read().is_ok());
+    }
+
+    #[test]
+    fn latch_through_pool() {
+        let dir = tempdir().unwrap();
+        let pm = page_manager(&dir.path().join("testfile.bin"), 2);
+        let mut pool = BufferPool::new(pm, 2);
+        let frame = pool.pin(0).unwrap();
+        let other = pool.pin(1).unwrap();
+
+        let first = pool.latch_read(frame);
+        let second = pool.latch_read(frame);
+        assert!(pool.buffers[frame].latch.try_write().is_err());
+        // Latches are per frame
+        drop(pool.latch_write(other));
+        drop((first, second));
+
+        let guard = pool.latch_write(frame);
+        assert!(pool.buffers[frame].latch.try_read().is_err());
+        drop(guard);
+        assert!(pool.buffers[frame].latch.try_write().is_ok());
+        pool.unpin(frame, false);
+        pool.unpin(other, false);
+    }
+}
@@ -0,0 +1,132 @@
+/*
+Length-prefixed framing shared by the on-page record formats. A frame looks like
+------------------------------------
+| length (2 bytes) |      data     |
+------------------------------------
+
+The length is big-endian like the rest of the page headers, so a single frame carries at most
+u16::MAX bytes. Zero-length frames are valid.
+*/
+
+use std::io::{self, ErrorKind};
+
+pub const FRAME_HEADER_LEN: usize = size_of::<u16>();
+pub const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
+pub fn frame_len(data_len: usize) -> usize {
+    FRAME_HEADER_LEN + data_len
+}
+
+// Writes a frame to the start of buf and returns the number of bytes used
+pub fn write_frame(buf: &mut [u8], data: &[u8]) -> Result<usize, io::Error> {
+    if data.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Frame of {} bytes exceeds the maximum of {}",
+                data.len(),
+                MAX_FRAME_LEN
+            ),
+        ));
+    }
+    let len = frame_len(data.len());
+    if buf.len() < len {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Frame of {} bytes doesn't fit in {} bytes", len, buf.len()),
+        ));
+    }
+
+    buf[..FRAME_HEADER_LEN].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    buf[FRAME_HEADER_LEN..len].copy_from_slice(data);
+    Ok(len)
+}
+
+// Reads the frame at the start of buf, returning its data and whatever follows it
+pub fn read_frame(buf: &[u8]) -> Result<(&[u8], &[u8]), io::Error> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "Buffer is too small to hold a frame header",
+        ));
+    }
+    let data_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    let rest = &buf[FRAME_HEADER_LEN..];
+    if rest.len() < data_len {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "Frame claims {} bytes but only {} are left",
+                data_len,
+                rest.len()
+            ),
+        ));
+    }
+
+    Ok(rest.split_at(data_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn round_trip_multiple_frames() {
+        let max = vec![9; MAX_FRAME_LEN];
+        let records: [&[u8]; 4] = [b"hello", b"", &max, b"x"];
+
+        let total: usize = records.iter().map(|record| frame_len(record.len())).sum();
+        let mut buf = vec![0; total];
+        let mut written = 0;
+        for record in records {
+            written += write_frame(&mut buf[written..], record).unwrap();
+        }
+        assert_eq!(written, total);
+
+        let mut rest = buf.as_slice();
+        for record in records {
+            let (data, next) = read_frame(rest).unwrap();
+            assert_eq!(data, record);
+            rest = next;
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn empty_frame_is_only_header() {
+        let mut buf = [1; FRAME_HEADER_LEN];
+        assert_eq!(write_frame(&mut buf, b"").unwrap(), FRAME_HEADER_LEN);
+        assert_eq!(buf, [0, 0]);
+        assert_eq!(read_frame(&buf).unwrap(), (&b""[..], &b""[..]));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut buf = vec![0; frame_len(MAX_FRAME_LEN + 1)];
+        let err = write_frame(&mut buf, &vec![0; MAX_FRAME_LEN + 1]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn frame_does_not_fit() {
+        let mut buf = [0; 4];
+        let err = write_frame(&mut buf, b"abc").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut buf = [0; 5];
+        write_frame(&mut buf, b"abc").unwrap();
+
+        assert_eq!(
+            read_frame(&buf[..1]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            read_frame(&buf[..4]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
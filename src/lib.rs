@@ -1,3 +1,9 @@
 pub mod cache;
+pub mod framing;
 pub mod log;
+pub mod merkle;
 pub mod page;
+pub mod queue;
+pub mod record;
+pub mod recovery;
+pub mod tx;
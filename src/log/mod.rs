@@ -1,22 +1,298 @@
 /*
 The Log Manager appends binary data to a file. The file consists of multiple pages which have the following format
---------------------------------------------------
-| offset (4 bytes) |      free space      | data |
---------------------------------------------------
+------------------------------------------------------------------------
+| offset (2 bytes) |      free space      | record n | ... | record 1 |
+------------------------------------------------------------------------
 
-Data grows from left to right. The offset points to the end of the free data. This makes it easy for readers to read newests log first
+Records are length-prefixed frames and grow from right to left. The offset points to the end of the free data, which is
+where the newest record starts. This makes it easy for readers to read newests log first
 */
 
-use std::io;
+use std::io::{self, ErrorKind};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::time::{Duration, Instant};
 
-use crate::page::{Page, PageManager};
+use crate::framing::{self, FRAME_HEADER_LEN};
+use crate::merkle::{self, Hash, MerkleProof};
+use crate::page::{Page, PageManager, PageWriter};
 
 pub struct LogManager {
-    log: PageManager,
+    pub(crate) log: PageManager,
     tail: Page,
     tail_index: usize,
     latest_lsn: u32,
     latest_flushed_lsn: u32,
+    // Newest commit record appended, and newest one known to be flushed
+    latest_commit_lsn: u32,
+    committed_lsn: u32,
+    poisoned: bool,
+    pages_on_disk: usize,
+    bytes_appended: u64,
+    flush_retries: usize,
+    durability: DurabilityMode,
+    last_sync: Instant,
+    syncs: usize,
+    pad_records: bool,
+    non_blocking: bool,
+    channel: Option<RecordChannel>,
+    // Tail offset as of the last flush, if the tail page is on disk at all
+    flushed_offset: Option<usize>,
+}
+
+const CACHE_LINE: usize = 64;
+
+// What flush does when the record channel is full
+// - Block waits for the consumer to make room
+// - Drop skips the record and counts it in dropped_records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhenFull {
+    Block,
+    Drop,
+}
+
+struct RecordChannel {
+    sender: SyncSender<Vec<u8>>,
+    when_full: WhenFull,
+    // Appended records waiting for the flush that makes them durable
+    pending: Vec<Vec<u8>>,
+    dropped: u64,
+}
+
+// How hard flush tries to get the tail onto stable storage
+// - Strict syncs the file on every flush
+// - Periodic syncs on a flush once at least the interval has passed since the last sync
+// - Relaxed only hands the tail to the OS and leaves writeback to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurabilityMode {
+    Strict,
+    Periodic(Duration),
+    Relaxed,
+}
+
+const DEFAULT_FLUSH_RETRIES: usize = 3;
+
+// Retries op as long as it fails with an error worth trying again, up to retries extra attempts
+fn retry_transient<T>(
+    retries: usize,
+    mut op: impl FnMut() -> Result<T, io::Error>,
+) -> Result<T, io::Error> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(err)
+                if matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock)
+                    && attempt < retries =>
+            {
+                attempt += 1
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogMetrics {
+    pub latest_lsn: u32,
+    pub flushed_lsn: u32,
+    // LSN of the newest commit record on disk, 0 before the first. Only commits appended through
+    // append_record count
+    pub committed_lsn: u32,
+    pub unflushed_records: u32,
+    pub bytes_appended: u64,
+    pub file_size: u64,
+    pub page_count: usize,
+}
+
+// Returned by append_async. It remembers the LSN of the record so the caller can find out later
+// whether it has reached the disk, or force it there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DurabilityHandle {
+    lsn: u32,
+}
+
+impl DurabilityHandle {
+    pub fn lsn(&self) -> u32 {
+        self.lsn
+    }
+
+    pub fn is_durable(&self, log: &LogManager) -> bool {
+        self.lsn <= log.latest_flushed_lsn
+    }
+
+    pub fn wait_durable(&self, log: &mut LogManager) -> Result<(), io::Error> {
+        if !self.is_durable(log) {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// What a record means to recovery. Serialized as a tag byte followed by the big-endian fields,
+// with the old and new bytes of an update framed (see crate::framing)
+// - Begin, Commit, Rollback: | tag | tx id (4 bytes) |
+// - Update: | tag | tx id (4 bytes) | page (4 bytes) | offset (2 bytes) | old | new |
+// - Checkpoint: | tag | count (2 bytes) | active tx ids (4 bytes each) |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogRecord {
+    Begin(i32),
+    Commit(i32),
+    Rollback(i32),
+    Update {
+        tx_id: i32,
+        page: u32,
+        offset: u16,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    // Transactions that were active when the checkpoint was taken
+    Checkpoint(Vec<i32>),
+}
+
+const TAG_BEGIN: u8 = 1;
+const TAG_COMMIT: u8 = 2;
+const TAG_ROLLBACK: u8 = 3;
+const TAG_UPDATE: u8 = 4;
+const TAG_CHECKPOINT: u8 = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogError {
+    Empty,
+    UnknownTag(u8),
+    Truncated { tag: u8, len: usize },
+    TrailingBytes { tag: u8, extra: usize },
+}
+
+impl std::fmt::Display for LogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogError::Empty => write!(f, "Log record is empty"),
+            LogError::UnknownTag(tag) => write!(f, "Unknown log record tag {}", tag),
+            LogError::Truncated { tag, len } => {
+                write!(f, "Log record with tag {} is cut off at {} bytes", tag, len)
+            }
+            LogError::TrailingBytes { tag, extra } => write!(
+                f,
+                "Log record with tag {} has {} bytes past its end",
+                tag, extra
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<LogError> for io::Error {
+    fn from(err: LogError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+impl LogRecord {
+    pub fn tx_id(&self) -> Option<i32> {
+        match self {
+            LogRecord::Begin(tx_id) | LogRecord::Commit(tx_id) | LogRecord::Rollback(tx_id) => {
+                Some(*tx_id)
+            }
+            LogRecord::Update { tx_id, .. } => Some(*tx_id),
+            LogRecord::Checkpoint(_) => None,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let (tag, tx_id) = match self {
+            LogRecord::Begin(tx_id) => (TAG_BEGIN, *tx_id),
+            LogRecord::Commit(tx_id) => (TAG_COMMIT, *tx_id),
+            LogRecord::Rollback(tx_id) => (TAG_ROLLBACK, *tx_id),
+            LogRecord::Update { tx_id, .. } => (TAG_UPDATE, *tx_id),
+            LogRecord::Checkpoint(active) => {
+                let count: u16 = active
+                    .len()
+                    .try_into()
+                    .expect("Too many active transactions for a checkpoint");
+                let mut bytes = vec![TAG_CHECKPOINT];
+                bytes.extend_from_slice(&count.to_be_bytes());
+                for tx_id in active {
+                    bytes.extend_from_slice(&tx_id.to_be_bytes());
+                }
+                return bytes;
+            }
+        };
+        let mut bytes = vec![tag];
+        bytes.extend_from_slice(&tx_id.to_be_bytes());
+
+        if let LogRecord::Update {
+            page,
+            offset,
+            old,
+            new,
+            ..
+        } = self
+        {
+            bytes.extend_from_slice(&page.to_be_bytes());
+            bytes.extend_from_slice(&offset.to_be_bytes());
+            for data in [old, new] {
+                let start = bytes.len();
+                bytes.resize(start + framing::frame_len(data.len()), 0);
+                framing::write_frame(&mut bytes[start..], data)
+                    .expect("Update images have to fit in a frame");
+            }
+        }
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<LogRecord, LogError> {
+        let (&tag, rest) = bytes.split_first().ok_or(LogError::Empty)?;
+        let truncated = || LogError::Truncated {
+            tag,
+            len: bytes.len(),
+        };
+        match tag {
+            TAG_CHECKPOINT => {
+                let (count, mut rest) = rest.split_first_chunk::<2>().ok_or_else(truncated)?;
+                let mut active = Vec::new();
+                for _ in 0..u16::from_be_bytes(*count) {
+                    let (tx_id, next) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+                    active.push(i32::from_be_bytes(*tx_id));
+                    rest = next;
+                }
+                return finish(tag, rest, LogRecord::Checkpoint(active));
+            }
+            TAG_BEGIN..=TAG_UPDATE => {}
+            _ => return Err(LogError::UnknownTag(tag)),
+        }
+
+        let (tx_id, rest) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+        let tx_id = i32::from_be_bytes(*tx_id);
+        match tag {
+            TAG_BEGIN => finish(tag, rest, LogRecord::Begin(tx_id)),
+            TAG_COMMIT => finish(tag, rest, LogRecord::Commit(tx_id)),
+            TAG_ROLLBACK => finish(tag, rest, LogRecord::Rollback(tx_id)),
+            _ => {
+                let (page, rest) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+                let (offset, rest) = rest.split_first_chunk::<2>().ok_or_else(truncated)?;
+                let (old, rest) = framing::read_frame(rest).map_err(|_| truncated())?;
+                let (new, rest) = framing::read_frame(rest).map_err(|_| truncated())?;
+                let record = LogRecord::Update {
+                    tx_id,
+                    page: u32::from_be_bytes(*page),
+                    offset: u16::from_be_bytes(*offset),
+                    old: old.to_vec(),
+                    new: new.to_vec(),
+                };
+                finish(tag, rest, record)
+            }
+        }
+    }
+}
+
+fn finish(tag: u8, rest: &[u8], record: LogRecord) -> Result<LogRecord, LogError> {
+    if !rest.is_empty() {
+        return Err(LogError::TrailingBytes {
+            tag,
+            extra: rest.len(),
+        });
+    }
+    Ok(record)
 }
 
 impl Page {
@@ -28,6 +304,14 @@ impl Page {
         let offset: u16 = offset
             .try_into()
             .expect("Offset couldnt be converted to u16");
+        // Anything outside the record area throws off the free space math in append
+        let page_size = self.read().len();
+        assert!(
+            (size_of::<u16>()..=page_size).contains(&(offset as usize)),
+            "Tried setting offset {} in log page of size {}",
+            offset,
+            page_size
+        );
         self.mutate()[..2].copy_from_slice(&offset.to_be_bytes())
     }
 
@@ -37,18 +321,71 @@ impl Page {
 }
 
 impl LogManager {
+    // Syncs on every flush, so a flushed record (a commit, a checkpoint) survives a power loss.
+    // with_durability trades that for fewer syncs: Periodic can lose the flushes since the last
+    // sync, Relaxed anything the OS hadn't written back yet
     pub fn new(path: &str, page_size: usize) -> Result<Self, io::Error> {
+        Self::with_durability(path, page_size, DurabilityMode::Strict)
+    }
+
+    // Opens the log and drops the newest records of the tail page for as long as they belong to
+    // transactions without a commit record, which is everything newer than the newest commit.
+    // Recovery undoes uncommitted changes from those very records, so this is only safe once it
+    // has run or when uncommitted changes can't have reached the data pages
+    pub fn open_trimmed(path: &str, page_size: usize) -> Result<Self, io::Error> {
+        let mut log = Self::new(path, page_size)?;
+        log.trim_uncommitted_tail()?;
+        Ok(log)
+    }
+
+    // Returns how many records were dropped
+    fn trim_uncommitted_tail(&mut self) -> Result<usize, io::Error> {
+        let page_size = self.log.page_size;
+        let start = self.tail.get_offset() as usize;
+        let mut offset = start;
+        let mut trimmed = 0;
+        while offset < page_size {
+            let (record, rest) = framing::read_frame(&self.tail.read()[offset..])?;
+            let uncommitted = matches!(
+                LogRecord::deserialize(record),
+                Ok(LogRecord::Begin(_) | LogRecord::Update { .. } | LogRecord::Rollback(_))
+            );
+            if !uncommitted {
+                break;
+            }
+            offset = page_size - rest.len();
+            trimmed += 1;
+        }
+        if trimmed == 0 {
+            return Ok(0);
+        }
+
+        self.tail.mutate()[start..offset].fill(0);
+        self.tail.set_offset(offset);
+        self.log.write_page(self.tail_index, &self.tail)?;
+        self.log.sync_data()?;
+        self.flushed_offset = Some(offset);
+        Ok(trimmed)
+    }
+
+    pub fn with_durability(
+        path: &str,
+        page_size: usize,
+        durability: DurabilityMode,
+    ) -> Result<Self, io::Error> {
         let mut pm = PageManager::new(path, page_size)?;
-        let logsize = pm.file.metadata()?.len();
+        let pages_on_disk = pm.n_pages()?;
 
         // Generate new tail if log hasnt been initialized. Else, load tail from last page
-        let (tail, tail_index) = if logsize == 0 {
+        let (tail, tail_index, flushed_offset) = if pages_on_disk == 0 {
             let mut page = Page::new(page_size);
             page.set_offset(page_size);
-            (page, 0)
+            (page, 0, None)
         } else {
-            let tail_index = pm.n_pages()? - 1;
-            (pm.read_page(tail_index)?, tail_index)
+            let tail_index = pages_on_disk - 1;
+            let tail = pm.read_page(tail_index)?;
+            let flushed_offset = tail.get_offset() as usize;
+            (tail, tail_index, Some(flushed_offset))
         };
 
         Ok(Self {
@@ -57,61 +394,408 @@ impl LogManager {
             tail_index,
             latest_lsn: 0,
             latest_flushed_lsn: 0,
+            latest_commit_lsn: 0,
+            committed_lsn: 0,
+            poisoned: false,
+            pages_on_disk,
+            bytes_appended: 0,
+            flush_retries: DEFAULT_FLUSH_RETRIES,
+            durability,
+            last_sync: Instant::now(),
+            syncs: 0,
+            pad_records: false,
+            non_blocking: false,
+            channel: None,
+            flushed_offset,
         })
     }
 
     pub fn flush_since_lsn(&mut self, lsn: u32) -> Result<(), io::Error> {
-        if lsn >= self.latest_flushed_lsn {
+        if lsn > self.latest_flushed_lsn {
             self.flush()?;
         }
         Ok(())
     }
 
+    pub fn set_flush_retries(&mut self, retries: usize) {
+        self.flush_retries = retries;
+    }
+
+    // Starts every record appended from now on at a cache line boundary within its page, so
+    // records written by different threads never share a line once the page is mapped. The gap
+    // up to the previous record is left zeroed, so the log has to be read with the same setting
+    pub fn set_record_padding(&mut self, enabled: bool) {
+        self.pad_records = enabled;
+    }
+
+    // Makes appends that would have to flush a full tail fail with WouldBlock instead, so the
+    // caller can flush when it suits it and try again. Once the full tail is flushed the next
+    // append moves on to a new tail without any further writes
+    pub fn set_non_blocking(&mut self, enabled: bool) {
+        self.non_blocking = enabled;
+    }
+
+    // Where a frame of len bytes ending at offset would start, if it fits behind the header
+    fn record_start(&self, offset: usize, len: usize) -> Option<usize> {
+        let start = offset.checked_sub(len)?;
+        let start = if self.pad_records {
+            start - start % CACHE_LINE
+        } else {
+            start
+        };
+        (start >= size_of::<u16>()).then_some(start)
+    }
+
     pub fn flush(&mut self) -> Result<(), io::Error> {
-        let result = self.log.write_page(self.tail_index, &self.tail);
+        // Records only get added below the offset, so once the tail page is on disk only the new
+        // records and the header need writing. The records go first so the header never points at
+        // bytes that aren't there. Every attempt writes the same bytes, so a retry after a partial
+        // write still ends up with the right bytes on disk
+        let (log, tail) = (&mut self.log, &self.tail);
+        let offset = tail.get_offset() as usize;
+        let written = retry_transient(self.flush_retries, || {
+            match self.flushed_offset {
+                Some(flushed) if flushed == offset => Ok(()),
+                Some(flushed) => log
+                    .write_bytes(self.tail_index, offset, &tail.read()[offset..flushed])
+                    .and_then(|_| {
+                        log.write_bytes(self.tail_index, 0, &tail.read()[..size_of::<u16>()])
+                    }),
+                None => log.write_page(self.tail_index, tail),
+            }
+            .map_err(io::Error::from)
+        })
+        .and_then(|_| self.sync_for_durability());
+
+        // A failed write leaves us not knowing what made it to disk, so refuse further appends
+        // until the tail has been written successfully again
+        if let Err(err) = written {
+            self.poisoned = true;
+            return Err(err);
+        }
         self.latest_flushed_lsn = self.latest_lsn;
-        result
+        self.committed_lsn = self.latest_commit_lsn;
+        self.flushed_offset = Some(offset);
+        self.pages_on_disk = self.pages_on_disk.max(self.tail_index + 1);
+        self.send_flushed();
+        Ok(())
+    }
+
+    // Sends every record appended from now on to sender once a flush has made it durable, oldest
+    // first. The channel is dropped for good once the receiver goes away
+    pub fn set_record_channel(&mut self, sender: SyncSender<Vec<u8>>, when_full: WhenFull) {
+        self.channel = Some(RecordChannel {
+            sender,
+            when_full,
+            pending: Vec::new(),
+            dropped: 0,
+        });
+    }
+
+    pub fn dropped_records(&self) -> u64 {
+        self.channel.as_ref().map_or(0, |channel| channel.dropped)
+    }
+
+    fn queue_for_channel(&mut self, offset: usize) {
+        if let Some(channel) = self.channel.as_mut() {
+            let (record, _) = framing::read_frame(&self.tail.read()[offset..])
+                .expect("The record was just written");
+            channel.pending.push(record.to_vec());
+        }
+    }
+
+    fn send_flushed(&mut self) {
+        let Some(channel) = self.channel.as_mut() else {
+            return;
+        };
+        for record in std::mem::take(&mut channel.pending) {
+            let sent = match channel.when_full {
+                WhenFull::Block => channel.sender.send(record).map_err(|_| ()),
+                WhenFull::Drop => match channel.sender.try_send(record) {
+                    Err(TrySendError::Full(_)) => {
+                        channel.dropped += 1;
+                        Ok(())
+                    }
+                    sent => sent.map_err(|_| ()),
+                },
+            };
+            if sent.is_err() {
+                self.channel = None;
+                return;
+            }
+        }
+    }
+
+    fn sync_for_durability(&mut self) -> Result<(), io::Error> {
+        let due = match self.durability {
+            DurabilityMode::Strict => true,
+            DurabilityMode::Periodic(interval) => self.last_sync.elapsed() >= interval,
+            DurabilityMode::Relaxed => false,
+        };
+        if due {
+            retry_transient(self.flush_retries, || self.log.sync_data())?;
+            self.last_sync = Instant::now();
+            self.syncs += 1;
+        }
+        Ok(())
+    }
+
+    // Everything here is tracked in memory, so taking a snapshot never touches the file
+    pub fn metrics(&self) -> LogMetrics {
+        LogMetrics {
+            latest_lsn: self.latest_lsn,
+            flushed_lsn: self.latest_flushed_lsn,
+            committed_lsn: self.committed_lsn,
+            unflushed_records: self.latest_lsn - self.latest_flushed_lsn,
+            bytes_appended: self.bytes_appended,
+            file_size: (self.pages_on_disk * self.log.page_size) as u64,
+            page_count: self.tail_index + 1,
+        }
     }
 
-    pub fn append(&mut self, data: &[u8]) -> Result<(), io::Error> {
-        let mut offset = self.tail.get_offset() as usize;
-        let freespace = offset - size_of::<u16>();
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    pub fn recover(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        self.poisoned = false;
+        Ok(())
+    }
+
+    pub fn append_async(&mut self, data: &[u8]) -> Result<DurabilityHandle, io::Error> {
+        let lsn = self.append(data)?;
+        Ok(DurabilityHandle { lsn })
+    }
 
-        if data.len() > (self.log.page_size - size_of::<u16>()) {
+    // Makes sure the tail has room for a record of len bytes, starting a new tail page if needed,
+    // and returns the current tail offset
+    fn reserve(&mut self, len: usize) -> Result<usize, io::Error> {
+        if self.poisoned {
+            return Err(io::Error::other(
+                "Log is poisoned after a failed flush, recover it before appending",
+            ));
+        }
+
+        let offset = self.tail.get_offset() as usize;
+        let frame_len = framing::frame_len(len);
+
+        if self.record_start(self.log.page_size, frame_len).is_none() {
             panic!(
                 "Tried writing log entry of size {} with page size {}",
-                data.len(),
-                self.log.page_size
+                len, self.log.page_size
             );
         };
 
-        if freespace < data.len() {
+        if self.record_start(offset, frame_len).is_none() {
+            if self.non_blocking && self.latest_lsn > self.latest_flushed_lsn {
+                return Err(io::Error::new(
+                    ErrorKind::WouldBlock,
+                    "Log tail is full and waiting to be flushed",
+                ));
+            }
             self.flush()?;
             self.tail = Page::new(self.log.page_size);
             self.tail_index += 1;
+            self.flushed_offset = None;
             self.tail.set_offset(self.log.page_size);
-            offset = self.log.page_size;
+            return Ok(self.log.page_size);
         }
-        let new_offset = offset - data.len();
-        self.tail.mutate()[new_offset..offset].copy_from_slice(data);
+        Ok(offset)
+    }
+
+    // Returns the LSN of the new record, which is what flush_since_lsn takes
+    pub fn append(&mut self, data: &[u8]) -> Result<u32, io::Error> {
+        let offset = self.reserve(data.len())?;
+        let new_offset = self
+            .record_start(offset, framing::frame_len(data.len()))
+            .expect("Reserved space should fit the record");
+        framing::write_frame(&mut self.tail.mutate()[new_offset..offset], data)?;
         self.tail.set_offset(new_offset);
+        self.queue_for_channel(new_offset);
         self.latest_lsn += 1;
-        Ok(())
+        self.bytes_appended += data.len() as u64;
+        Ok(self.latest_lsn)
+    }
+
+    pub fn append_record(&mut self, record: &LogRecord) -> Result<u32, io::Error> {
+        let lsn = self.append(&record.serialize())?;
+        if let LogRecord::Commit(_) = record {
+            self.latest_commit_lsn = lsn;
+        }
+        Ok(lsn)
+    }
+
+    // Writes a checkpoint record and flushes the log, returning the record's LSN. Recovery
+    // trusts every page change before the checkpoint to be on disk, except those of active_txs,
+    // so the pages have to be flushed first and nothing may change a page until this returns.
+    // BufferPool::checkpoint takes care of both
+    pub fn checkpoint(&mut self, active_txs: &[i32]) -> Result<u32, io::Error> {
+        let lsn = self.append_record(&LogRecord::Checkpoint(active_txs.to_vec()))?;
+        self.flush()?;
+        Ok(lsn)
+    }
+
+    // Lets f write a record of at most max_len bytes straight into the tail page. Writing past
+    // max_len fails and leaves the log as it was
+    pub fn append_with<F>(&mut self, max_len: usize, f: F) -> Result<u32, io::Error>
+    where
+        F: FnOnce(&mut PageWriter) -> Result<(), io::Error>,
+    {
+        let offset = self.reserve(max_len)?;
+        let start = offset - framing::frame_len(max_len);
+        let body = start + FRAME_HEADER_LEN;
+
+        let mut writer = PageWriter::new(&mut self.tail.mutate()[body..offset]);
+        if let Err(err) = f(&mut writer) {
+            self.tail.mutate()[start..offset].fill(0);
+            return Err(err);
+        }
+        let len = writer.position();
+
+        // Records sit flush against the previous one (or its padding), so slide it over the
+        // unused reservation and clear whatever the writer left behind
+        let frame_len = framing::frame_len(len);
+        let new_offset = self
+            .record_start(offset, frame_len)
+            .expect("Reserved space should fit the record");
+        let page = self.tail.mutate();
+        page.copy_within(body..body + len, new_offset + FRAME_HEADER_LEN);
+        page[new_offset..new_offset + FRAME_HEADER_LEN]
+            .copy_from_slice(&(len as u16).to_be_bytes());
+        page[start.min(new_offset)..new_offset].fill(0);
+        page[new_offset + frame_len..offset].fill(0);
+        self.tail.set_offset(new_offset);
+        self.queue_for_channel(new_offset);
+        self.latest_lsn += 1;
+        self.bytes_appended += len as u64;
+        Ok(self.latest_lsn)
+    }
+
+    // Walks the log from the newest record back to the oldest. The tail is read from memory, so
+    // records that haven't been flushed yet are included
+    pub fn iter(&mut self) -> LogIterator<'_> {
+        LogIterator {
+            offset: self.tail.get_offset() as usize,
+            page: self.tail.clone(),
+            position: self.tail_index,
+            pad_records: self.pad_records,
+            log: &mut self.log,
+        }
+    }
+
+    fn merkle_leaves(&mut self) -> Result<Vec<Hash>, io::Error> {
+        let mut leaves = self
+            .iter()
+            .map(|record| record.map(|record| merkle::leaf_hash(&record)))
+            .collect::<Result<Vec<_>, _>>()?;
+        leaves.reverse();
+        Ok(leaves)
+    }
+
+    // Covers every record in the log, oldest first, flushed or not
+    pub fn merkle_root(&mut self) -> Result<Hash, io::Error> {
+        Ok(merkle::root(&self.merkle_leaves()?))
+    }
+
+    // LSNs restart at 1 for every LogManager, so only records appended through this one can be
+    // proven. They're the newest latest_lsn records in the log
+    pub fn merkle_proof(&mut self, lsn: u32) -> Result<MerkleProof, io::Error> {
+        if lsn == 0 || lsn > self.latest_lsn {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Tried proving LSN {} with latest LSN {}",
+                    lsn, self.latest_lsn
+                ),
+            ));
+        }
+        let leaves = self.merkle_leaves()?;
+        let index = leaves.len() - (self.latest_lsn - lsn) as usize - 1;
+        Ok(merkle::proof(&leaves, index))
+    }
+}
+
+pub struct LogIterator<'a> {
+    log: &'a mut PageManager,
+    page: Page,
+    position: usize,
+    offset: usize,
+    pad_records: bool,
+}
+
+impl Iterator for LogIterator<'_> {
+    type Item = Result<Vec<u8>, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let page_size = self.page.read().len();
+        while self.offset >= page_size {
+            if self.position == 0 {
+                return None;
+            }
+            self.position -= 1;
+            match self.log.read_page(self.position) {
+                Ok(page) => {
+                    self.offset = page.get_offset() as usize;
+                    self.page = page;
+                }
+                Err(err) => {
+                    self.position = 0;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        let (record, rest) = match framing::read_frame(&self.page.read()[self.offset..]) {
+            Ok(frame) => frame,
+            Err(err) => {
+                // Nothing after a broken frame can be trusted, so end the walk here
+                self.position = 0;
+                self.offset = page_size;
+                return Some(Err(err));
+            }
+        };
+        let record = record.to_vec();
+        self.offset = page_size - rest.len();
+        if self.pad_records {
+            self.offset = self.offset.next_multiple_of(CACHE_LINE).min(page_size);
+        }
+        Some(Ok(record))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::page::{FileStorage, MemStorage, Storage};
     use pretty_assertions::assert_eq;
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+    use std::thread;
     use tempfile::tempdir;
-    const PAGESIZE: usize = 8;
+    const PAGESIZE: usize = 16;
 
     #[test]
     fn offset_setter_getter() {
         let mut page = Page::new(PAGESIZE);
         page.set_offset(PAGESIZE);
         assert_eq!(page.get_offset(), PAGESIZE as u16);
+        page.set_offset(2);
+        assert_eq!(page.get_offset(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn offset_past_page() {
+        Page::new(PAGESIZE).set_offset(PAGESIZE + 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn offset_inside_header() {
+        Page::new(PAGESIZE).set_offset(1);
     }
 
     #[test]
@@ -134,12 +818,13 @@ mod tests {
 
         let log_data = b"A";
         lm.append(log_data).unwrap();
-        assert_eq!(lm.tail.read(), &vec![0, 7, 0, 0, 0, 0, 0, 65]);
+        let expected = [vec![0, 13], vec![0; 11], vec![0, 1, 65]].concat();
+        assert_eq!(lm.tail.read(), &expected);
         lm.flush().unwrap();
-        assert_eq!(lm.tail.read(), &vec![0, 7, 0, 0, 0, 0, 0, 65]);
+        assert_eq!(lm.tail.read(), &expected);
 
         let data = lm.log.read_page(0).unwrap();
-        assert_eq!(data.read(), &vec![0, 7, 0, 0, 0, 0, 0, 65]);
+        assert_eq!(data.read(), &expected);
     }
 
     #[test]
@@ -152,11 +837,209 @@ mod tests {
         lm.append(b"B").unwrap();
         lm.append(b"C").unwrap();
 
-        assert_eq!(lm.tail.read(), &vec![0, 5, 0, 0, 0, 67, 66, 65]);
+        let expected = [vec![0, 7], vec![0; 5], vec![0, 1, 67, 0, 1, 66, 0, 1, 65]].concat();
+        assert_eq!(lm.tail.read(), &expected);
         lm.flush().unwrap();
-        assert_eq!(lm.tail.read(), &vec![0, 5, 0, 0, 0, 67, 66, 65]);
+        assert_eq!(lm.tail.read(), &expected);
         let data = lm.log.read_page(0).unwrap();
-        assert_eq!(data.read(), &vec![0, 5, 0, 0, 0, 67, 66, 65]);
+        assert_eq!(data.read(), &expected);
+    }
+
+    #[test]
+    fn append_returns_lsn() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        // Rolling over to a new page doesn't skip or repeat LSNs
+        for expected in 1..=10 {
+            assert_eq!(lm.append(b"AA").unwrap(), expected);
+        }
+        let lsn = lm.append_with(2, |writer| writer.put_bytes(b"B")).unwrap();
+        assert_eq!(lsn, 11);
+        assert_eq!(lm.append_async(b"C").unwrap().lsn(), 12);
+        assert_eq!(lm.latest_lsn, 12);
+    }
+
+    #[test]
+    fn stream_flushed_records() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(2);
+        lm.set_record_channel(sender, WhenFull::Block);
+
+        let consumer = thread::spawn(move || receiver.iter().collect::<Vec<_>>());
+        let records: Vec<Vec<u8>> = (0..10).map(|i| vec![i as u8; i % 4 + 1]).collect();
+        for (i, record) in records.iter().enumerate() {
+            if i % 2 == 0 {
+                lm.append(record).unwrap();
+            } else {
+                lm.append_with(4, |writer| writer.put_bytes(record))
+                    .unwrap();
+            }
+        }
+        lm.flush().unwrap();
+        drop(lm);
+
+        assert_eq!(consumer.join().unwrap(), records);
+    }
+
+    #[test]
+    fn stream_waits_for_flush() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), 64).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(2);
+        lm.set_record_channel(sender, WhenFull::Drop);
+
+        for i in 0..4 {
+            lm.append(&[i]).unwrap();
+        }
+        assert!(receiver.try_recv().is_err());
+
+        lm.flush().unwrap();
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![vec![0], vec![1]]
+        );
+        assert_eq!(lm.dropped_records(), 2);
+
+        lm.append(&[4]).unwrap();
+        lm.flush().unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![vec![4]]);
+
+        // Once the receiver is gone flushing carries on without it
+        drop(receiver);
+        lm.append(&[5]).unwrap();
+        lm.flush().unwrap();
+        assert!(lm.channel.is_none());
+    }
+
+    #[test]
+    fn flush_since_lsn_skips_flushed() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let writes = Rc::new(Cell::new(0));
+        let hook_writes = Rc::clone(&writes);
+        lm.log
+            .set_write_hook(move |_, _| hook_writes.set(hook_writes.get() + 1));
+
+        let first = lm.append(b"A").unwrap();
+        let second = lm.append(b"B").unwrap();
+        lm.flush_since_lsn(first).unwrap();
+        assert_eq!(writes.get(), 1);
+        assert_eq!(lm.latest_flushed_lsn, second);
+
+        // Both are on disk already
+        lm.flush_since_lsn(first).unwrap();
+        lm.flush_since_lsn(second).unwrap();
+        assert_eq!(writes.get(), 1);
+
+        let third = lm.append(b"C").unwrap();
+        lm.flush_since_lsn(second).unwrap();
+        assert_eq!(writes.get(), 1);
+        // The new record and the header are written separately
+        lm.flush_since_lsn(third).unwrap();
+        assert_eq!(writes.get(), 3);
+    }
+
+    // Passes everything through to inner, counting the bytes written
+    struct CountingStorage {
+        inner: Box<dyn Storage>,
+        written: Rc<Cell<usize>>,
+    }
+
+    impl io::Read for CountingStorage {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl io::Write for CountingStorage {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = self.inner.write(buf)?;
+            self.written.set(self.written.get() + n);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl io::Seek for CountingStorage {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Storage for CountingStorage {
+        fn size(&self) -> Result<u64, io::Error> {
+            self.inner.size()
+        }
+
+        fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+            self.inner.set_len(len)
+        }
+
+        fn sync_all(&mut self) -> Result<(), io::Error> {
+            self.inner.sync_all()
+        }
+
+        fn sync_data(&mut self) -> Result<(), io::Error> {
+            self.inner.sync_data()
+        }
+    }
+
+    #[test]
+    fn flush_writes_only_new_records() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let written = Rc::new(Cell::new(0));
+        let inner = std::mem::replace(&mut lm.log.storage, Box::new(MemStorage::new()));
+        lm.log.storage = Box::new(CountingStorage {
+            inner,
+            written: Rc::clone(&written),
+        });
+
+        lm.append(b"A").unwrap();
+        lm.flush().unwrap();
+        assert_eq!(written.replace(0), PAGESIZE);
+
+        lm.append(b"BB").unwrap();
+        lm.append(b"C").unwrap();
+        lm.flush().unwrap();
+        assert_eq!(written.replace(0), 2 + 4 + 3);
+        lm.flush().unwrap();
+        assert_eq!(written.get(), 0);
+
+        let expected = [
+            vec![0, 6],
+            vec![0; 4],
+            vec![0, 1, 67, 0, 2, 66, 66, 0, 1, 65],
+        ]
+        .concat();
+        assert_eq!(lm.log.read_page(0).unwrap().read(), &expected);
+
+        // Picks up where the flushed tail left off after reopening
+        drop(lm);
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let inner = std::mem::replace(&mut lm.log.storage, Box::new(MemStorage::new()));
+        lm.log.storage = Box::new(CountingStorage {
+            inner,
+            written: Rc::clone(&written),
+        });
+        lm.append(b"D").unwrap();
+        lm.flush().unwrap();
+        assert_eq!(written.get(), 2 + 3);
+        let read: Vec<Vec<u8>> = lm.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            read,
+            vec![b"D".to_vec(), b"C".to_vec(), b"BB".to_vec(), b"A".to_vec()]
+        );
     }
 
     #[test]
@@ -170,10 +1053,14 @@ mod tests {
         lm.append(b"CC").unwrap();
         lm.append(b"D").unwrap();
 
-        assert_eq!(lm.tail.read(), &vec![0, 7, 0, 0, 0, 0, 0, 68]);
+        let expected = [vec![0, 13], vec![0; 11], vec![0, 1, 68]].concat();
+        assert_eq!(lm.tail.read(), &expected);
 
         let data = lm.log.read_page(0).unwrap();
-        assert_eq!(data.read(), &vec![0, 2, 67, 67, 66, 66, 65, 65]);
+        assert_eq!(
+            data.read(),
+            &vec![0, 4, 0, 0, 0, 2, 67, 67, 0, 2, 66, 66, 0, 2, 65, 65]
+        );
     }
 
     #[test]
@@ -187,9 +1074,11 @@ mod tests {
         lm_old.append(b"CC").unwrap();
         lm_old.append(b"D").unwrap();
         lm_old.flush().unwrap();
+        drop(lm_old);
 
         let lm_new = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
-        assert_eq!(lm_new.tail.read(), &vec![0, 7, 0, 0, 0, 0, 0, 68]);
+        let expected = [vec![0, 13], vec![0; 11], vec![0, 1, 68]].concat();
+        assert_eq!(lm_new.tail.read(), &expected);
         assert_eq!(lm_new.tail_index, 1);
     }
 
@@ -209,12 +1098,556 @@ mod tests {
         let file_path = dir.path().join("logfile.bin");
         let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
 
-        lm.append(b"AAAAAA").unwrap();
-        assert_eq!(lm.tail.read(), &vec![0, 2, 65, 65, 65, 65, 65, 65]);
+        lm.append(&[65; PAGESIZE - 4]).unwrap();
+        let expected_a = [vec![0, 2, 0, 12], vec![65; 12]].concat();
+        assert_eq!(lm.tail.read(), &expected_a);
 
-        lm.append(b"BBBBBB").unwrap();
-        assert_eq!(lm.tail.read(), &vec![0, 2, 66, 66, 66, 66, 66, 66]);
+        lm.append(&[66; PAGESIZE - 4]).unwrap();
+        assert_eq!(lm.tail.read(), &[vec![0, 2, 0, 12], vec![66; 12]].concat());
         let data = lm.log.read_page(0).unwrap();
-        assert_eq!(data.read(), &vec![0, 2, 65, 65, 65, 65, 65, 65]);
+        assert_eq!(data.read(), &expected_a);
+    }
+
+    #[test]
+    fn metrics_snapshot() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let metrics = lm.metrics();
+        assert_eq!(metrics.latest_lsn, 0);
+        assert_eq!(metrics.file_size, 0);
+        assert_eq!(metrics.page_count, 1);
+
+        lm.append(b"AA").unwrap();
+        lm.append(b"BB").unwrap();
+        lm.append(b"CC").unwrap();
+        lm.append(b"D").unwrap();
+
+        // Rolling over to the second page flushed the first one
+        let metrics = lm.metrics();
+        assert_eq!(
+            metrics,
+            LogMetrics {
+                latest_lsn: 4,
+                flushed_lsn: 3,
+                committed_lsn: 0,
+                unflushed_records: 1,
+                bytes_appended: 7,
+                file_size: PAGESIZE as u64,
+                page_count: 2,
+            }
+        );
+
+        lm.flush().unwrap();
+        let metrics = lm.metrics();
+        assert_eq!(metrics.unflushed_records, 0);
+        assert_eq!(metrics.flushed_lsn, metrics.latest_lsn);
+        assert_eq!(metrics.file_size, lm.log.storage.size().unwrap());
+        assert_eq!(metrics.page_count, lm.log.n_pages().unwrap());
+
+        // A commit only counts once it is flushed
+        let ordered = |metrics: LogMetrics| {
+            assert!(metrics.committed_lsn <= metrics.flushed_lsn);
+            assert!(metrics.flushed_lsn <= metrics.latest_lsn);
+        };
+        let commit = lm.append_record(&LogRecord::Commit(1)).unwrap();
+        ordered(lm.metrics());
+        assert_eq!(lm.metrics().committed_lsn, 0);
+        lm.flush().unwrap();
+        ordered(lm.metrics());
+        assert_eq!(lm.metrics().committed_lsn, commit);
+
+        lm.append_record(&LogRecord::Begin(2)).unwrap();
+        lm.append_record(&LogRecord::Commit(2)).unwrap();
+        ordered(lm.metrics());
+        assert_eq!(lm.metrics().committed_lsn, commit);
+        lm.append_record(&LogRecord::Begin(3)).unwrap();
+        lm.flush().unwrap();
+        ordered(lm.metrics());
+        assert_eq!(lm.metrics().committed_lsn, commit + 2);
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let mut attempts = 0;
+        let result = retry_transient(3, || {
+            attempts += 1;
+            if attempts <= 2 {
+                Err(io::Error::from(ErrorKind::Interrupted))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_transient(3, || {
+            attempts += 1;
+            Err(io::Error::from(ErrorKind::WouldBlock))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn retry_does_not_retry_fatal_errors() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_transient(3, || {
+            attempts += 1;
+            Err(io::Error::from(ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn strict_durability_syncs_every_flush() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::with_durability(
+            file_path.to_str().unwrap(),
+            PAGESIZE,
+            DurabilityMode::Strict,
+        )
+        .unwrap();
+
+        lm.append(b"A").unwrap();
+        lm.flush().unwrap();
+        lm.append(b"B").unwrap();
+        lm.flush().unwrap();
+        assert_eq!(lm.syncs, 2);
+
+        // The default, so commits and checkpoints are durable once flushed
+        drop(lm);
+        let lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        assert_eq!(lm.durability, DurabilityMode::Strict);
+    }
+
+    #[test]
+    fn relaxed_durability_never_syncs() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::with_durability(
+            file_path.to_str().unwrap(),
+            PAGESIZE,
+            DurabilityMode::Relaxed,
+        )
+        .unwrap();
+
+        lm.append(b"A").unwrap();
+        lm.flush().unwrap();
+        assert_eq!(lm.syncs, 0);
+        assert_eq!(lm.log.read_page(0).unwrap().read()[PAGESIZE - 1], 65);
+    }
+
+    #[test]
+    fn periodic_durability_syncs_after_interval() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::with_durability(
+            file_path.to_str().unwrap(),
+            PAGESIZE,
+            DurabilityMode::Periodic(Duration::from_secs(3600)),
+        )
+        .unwrap();
+
+        lm.append(b"A").unwrap();
+        lm.flush().unwrap();
+        assert_eq!(lm.syncs, 0);
+
+        lm.durability = DurabilityMode::Periodic(Duration::ZERO);
+        lm.flush().unwrap();
+        assert_eq!(lm.syncs, 1);
+    }
+
+    #[test]
+    fn durability_handles() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let handles: Vec<_> = [b"A", b"B", b"C"]
+            .iter()
+            .map(|data| lm.append_async(*data).unwrap())
+            .collect();
+        assert_eq!(
+            handles.iter().map(|h| h.lsn()).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(handles.iter().all(|h| !h.is_durable(&lm)));
+
+        lm.flush().unwrap();
+        assert!(handles.iter().all(|h| h.is_durable(&lm)));
+
+        let handle = lm.append_async(b"D").unwrap();
+        handle.wait_durable(&mut lm).unwrap();
+        assert!(handle.is_durable(&lm));
+        assert_eq!(lm.log.read_page(0).unwrap().read()[6], 68);
+    }
+
+    #[test]
+    fn append_with_writer() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let other_path = dir.path().join("other.bin");
+        let mut other = LogManager::new(other_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        lm.append_with(4, |writer| writer.put_bytes(b"AB")).unwrap();
+        lm.append_with(4, |writer| writer.put_u16(0x4344)).unwrap();
+        other.append(b"AB").unwrap();
+        other.append(b"CD").unwrap();
+
+        assert_eq!(lm.tail.read(), other.tail.read());
+        assert_eq!(
+            lm.tail.read(),
+            &vec![0, 8, 0, 0, 0, 0, 0, 0, 0, 2, 67, 68, 0, 2, 65, 66]
+        );
+        assert_eq!(lm.latest_lsn, 2);
+    }
+
+    #[test]
+    fn append_with_writer_over_max_len() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        lm.append(b"A").unwrap();
+        let err = lm
+            .append_with(2, |writer| writer.put_bytes(b"BCD"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert_eq!(lm.tail.get_offset(), 13);
+        assert!(lm.tail.read()[2..13].iter().all(|&byte| byte == 0));
+        assert_eq!(lm.latest_lsn, 1);
+    }
+
+    #[test]
+    fn padded_records_start_on_cache_lines() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), 256).unwrap();
+        lm.set_record_padding(true);
+
+        lm.append(&[1; 10]).unwrap();
+        assert_eq!(lm.tail.get_offset(), 192);
+        lm.append_with(100, |writer| writer.put_bytes(&[2; 70]))
+            .unwrap();
+        assert_eq!(lm.tail.get_offset(), 64);
+        assert_eq!(&lm.tail.read()[64..66], &[0, 70]);
+        assert_eq!(&lm.tail.read()[66..136], &[2; 70]);
+        assert!(lm.tail.read()[136..192].iter().all(|&byte| byte == 0));
+        assert_eq!(&lm.tail.read()[192..194], &[0, 10]);
+        assert_eq!(&lm.tail.read()[194..204], &[1; 10]);
+        assert!(lm.tail.read()[204..].iter().all(|&byte| byte == 0));
+
+        // Starting at 0 would run into the offset header
+        lm.append(&[3; 5]).unwrap();
+        assert_eq!(lm.tail_index, 1);
+        assert_eq!(lm.tail.get_offset(), 192);
+
+        lm.set_record_padding(false);
+        lm.append(&[4; 5]).unwrap();
+        assert_eq!(lm.tail.get_offset(), 185);
+    }
+
+    #[test]
+    #[should_panic]
+    fn padded_record_too_large() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), 128).unwrap();
+        lm.set_record_padding(true);
+        lm.append(&[1; 100]).unwrap();
+    }
+
+    #[test]
+    fn poisoned_after_failed_flush() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        lm.append(b"A").unwrap();
+
+        // Swap in a read-only handle so the write fails
+        let read_only = FileStorage::from(File::open(&file_path).unwrap());
+        let writable = std::mem::replace(&mut lm.log.storage, Box::new(read_only));
+        assert!(lm.flush().is_err());
+        assert!(lm.is_poisoned());
+        assert_eq!(lm.latest_flushed_lsn, 0);
+
+        let err = lm.append(b"B").unwrap_err();
+        assert!(err.to_string().contains("poisoned"));
+        assert_eq!(
+            lm.tail.read(),
+            &[vec![0, 13], vec![0; 11], vec![0, 1, 65]].concat()
+        );
+
+        lm.log.storage = writable;
+        lm.recover().unwrap();
+        assert!(!lm.is_poisoned());
+        lm.append(b"B").unwrap();
+        assert_eq!(
+            lm.tail.read(),
+            &[vec![0, 10], vec![0; 8], vec![0, 1, 66, 0, 1, 65]].concat()
+        );
+    }
+
+    #[test]
+    fn iterate_newest_first() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        assert_eq!(lm.iter().count(), 0);
+
+        let records: Vec<Vec<u8>> = (0..12).map(|i| vec![i as u8; i % 5 + 1]).collect();
+        for record in &records {
+            lm.append(record).unwrap();
+        }
+        assert!(lm.tail_index > 2);
+
+        let read: Vec<Vec<u8>> = lm.iter().collect::<Result<_, _>>().unwrap();
+        let expected: Vec<Vec<u8>> = records.iter().rev().cloned().collect();
+        assert_eq!(read, expected);
+
+        // Once flushed the tail reads back the same from disk
+        lm.flush().unwrap();
+        drop(lm);
+        let mut reopened = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let read: Vec<Vec<u8>> = reopened.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn non_blocking_append_on_full_tail() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        lm.set_non_blocking(true);
+
+        // Two of these fill a tail
+        lm.append(b"abc").unwrap();
+        lm.append(b"def").unwrap();
+        let err = lm.append(b"ghi").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert_eq!(lm.latest_lsn, 2);
+        assert_eq!(lm.tail_index, 0);
+        assert_eq!(lm.log.n_pages().unwrap(), 0);
+
+        lm.flush().unwrap();
+        assert_eq!(lm.append(b"ghi").unwrap(), 3);
+        assert_eq!(lm.tail_index, 1);
+
+        let read: Vec<Vec<u8>> = lm.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            read,
+            vec![b"ghi".to_vec(), b"def".to_vec(), b"abc".to_vec()]
+        );
+    }
+
+    #[test]
+    fn on_disk_byte_order() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        lm.append_record(&LogRecord::Commit(0x01020304)).unwrap();
+        lm.flush().unwrap();
+
+        // Header offset, then the frame length and the record, all big-endian
+        let raw = std::fs::read(&file_path).unwrap();
+        assert_eq!(&raw[..2], &[0, 9]);
+        assert_eq!(&raw[9..], &[0, 5, TAG_COMMIT, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn open_trims_uncommitted_tail() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), 128).unwrap();
+        let update = |tx_id| LogRecord::Update {
+            tx_id,
+            page: 0,
+            offset: 0,
+            old: vec![0; 2],
+            new: vec![1; 2],
+        };
+        let committed = [
+            LogRecord::Begin(1),
+            LogRecord::Begin(2),
+            update(1),
+            update(2),
+            LogRecord::Commit(1),
+        ];
+        for record in committed
+            .iter()
+            .chain(&[update(2), LogRecord::Begin(3), update(3)])
+        {
+            lm.append_record(record).unwrap();
+        }
+        lm.flush().unwrap();
+        drop(lm);
+
+        let mut lm = LogManager::open_trimmed(file_path.to_str().unwrap(), 128).unwrap();
+        let read: Vec<LogRecord> = lm
+            .iter()
+            .map(|bytes| LogRecord::deserialize(&bytes.unwrap()).unwrap())
+            .collect();
+        assert_eq!(read, committed.iter().rev().cloned().collect::<Vec<_>>());
+
+        // The trimmed tail is on disk, and appending carries on from it
+        lm.append_record(&LogRecord::Commit(2)).unwrap();
+        lm.flush().unwrap();
+        drop(lm);
+        let mut lm = LogManager::open_trimmed(file_path.to_str().unwrap(), 128).unwrap();
+        assert_eq!(lm.iter().count(), committed.len() + 1);
+        let newest = lm.iter().next().unwrap().unwrap();
+        assert_eq!(LogRecord::deserialize(&newest), Ok(LogRecord::Commit(2)));
+    }
+
+    #[test]
+    fn log_record_round_trip() {
+        let records = [
+            LogRecord::Begin(1),
+            LogRecord::Commit(i32::MAX),
+            LogRecord::Rollback(-1),
+            LogRecord::Update {
+                tx_id: 7,
+                page: 3,
+                offset: 12,
+                old: b"old".to_vec(),
+                new: b"newer".to_vec(),
+            },
+            LogRecord::Update {
+                tx_id: 7,
+                page: u32::MAX,
+                offset: u16::MAX,
+                old: Vec::new(),
+                new: Vec::new(),
+            },
+            LogRecord::Update {
+                tx_id: 8,
+                page: 0,
+                offset: 0,
+                old: Vec::new(),
+                new: b"new".to_vec(),
+            },
+            LogRecord::Checkpoint(Vec::new()),
+            LogRecord::Checkpoint(vec![3, -4, i32::MAX]),
+        ];
+        for record in records {
+            let bytes = record.serialize();
+            assert_eq!(LogRecord::deserialize(&bytes), Ok(record));
+        }
+    }
+
+    #[test]
+    fn log_record_malformed() {
+        assert_eq!(LogRecord::deserialize(&[]), Err(LogError::Empty));
+        assert_eq!(LogRecord::deserialize(&[0]), Err(LogError::UnknownTag(0)));
+        assert_eq!(
+            LogRecord::deserialize(&[TAG_COMMIT, 0, 0]),
+            Err(LogError::Truncated { tag: 2, len: 3 })
+        );
+        assert_eq!(
+            LogRecord::deserialize(&[TAG_CHECKPOINT, 0, 0, 0]),
+            Err(LogError::TrailingBytes { tag: 5, extra: 1 })
+        );
+        assert_eq!(
+            LogRecord::deserialize(&[TAG_CHECKPOINT, 0, 1, 0, 0]),
+            Err(LogError::Truncated { tag: 5, len: 5 })
+        );
+
+        // The new image claims more bytes than are left
+        let mut update = LogRecord::Update {
+            tx_id: 1,
+            page: 2,
+            offset: 3,
+            old: b"a".to_vec(),
+            new: b"bc".to_vec(),
+        }
+        .serialize();
+        update.pop();
+        let err = LogRecord::deserialize(&update).unwrap_err();
+        assert_eq!(err, LogError::Truncated { tag: 4, len: 17 });
+        assert_eq!(io::Error::from(err).kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn append_records() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), 64).unwrap();
+
+        let records = [
+            LogRecord::Begin(1),
+            LogRecord::Update {
+                tx_id: 1,
+                page: 0,
+                offset: 4,
+                old: vec![0; 4],
+                new: b"data".to_vec(),
+            },
+            LogRecord::Commit(1),
+        ];
+        for record in &records {
+            lm.append_record(record).unwrap();
+        }
+        let read: Vec<LogRecord> = lm
+            .iter()
+            .map(|bytes| LogRecord::deserialize(&bytes.unwrap()).unwrap())
+            .collect();
+        assert_eq!(read, records.iter().rev().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn merkle_root_detects_tampering() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let records: Vec<Vec<u8>> = (0..5).map(|i| vec![i as u8; 3]).collect();
+        for record in &records {
+            lm.append(record).unwrap();
+        }
+        lm.flush().unwrap();
+        assert!(lm.tail_index > 0);
+
+        let root = lm.merkle_root().unwrap();
+        assert_eq!(root, lm.merkle_root().unwrap());
+        let proof = lm.merkle_proof(1).unwrap();
+        assert!(proof.verify(&records[0], &root));
+        assert!(!proof.verify(&records[1], &root));
+
+        // The first record ends the first page
+        lm.log.write_bytes(0, PAGESIZE - 1, &[0xff]).unwrap();
+        let tampered = lm.merkle_root().unwrap();
+        assert_ne!(tampered, root);
+        assert!(!proof.verify(&records[0], &tampered));
+        // Nothing else changed, so the siblings still lead the altered record to the new root
+        assert!(proof.verify(&[0, 0, 0xff], &tampered));
+
+        let err = lm.merkle_proof(6).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn iterate_padded_records() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("logfile.bin");
+        let mut lm = LogManager::new(file_path.to_str().unwrap(), 256).unwrap();
+        lm.set_record_padding(true);
+
+        let records: Vec<Vec<u8>> = (0..6).map(|i| vec![i as u8; i * 20]).collect();
+        for record in &records {
+            lm.append(record).unwrap();
+        }
+        lm.append_with(30, |writer| writer.put_bytes(b"with"))
+            .unwrap();
+
+        let read: Vec<Vec<u8>> = lm.iter().collect::<Result<_, _>>().unwrap();
+        let mut expected: Vec<Vec<u8>> = records.iter().rev().cloned().collect();
+        expected.insert(0, b"with".to_vec());
+        assert_eq!(read, expected);
     }
 }
@@ -0,0 +1,256 @@
+/*
+A Merkle tree over byte records, hashed with SHA-256. Leaves and inner nodes are hashed with a
+different prefix byte so a leaf can never be passed off as a node
+------------------------------------------------------------
+| leaf = H(0x00 | record) | node = H(0x01 | left | right) |
+------------------------------------------------------------
+
+A level with an odd number of hashes carries its last one up unchanged instead of pairing it with
+a copy of itself, so no two different lists of records share a root.
+*/
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes(word.try_into().expect("Chunk is 4 bytes"));
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+// Hashes the concatenation of parts, which saves copying prefixes and children into one buffer
+fn sha256_parts(parts: &[&[u8]]) -> Hash {
+    let mut state = H0;
+    let mut block = [0u8; 64];
+    let mut filled = 0;
+    let mut total_len: u64 = 0;
+
+    for part in parts {
+        total_len += part.len() as u64;
+        for &byte in *part {
+            block[filled] = byte;
+            filled += 1;
+            if filled == block.len() {
+                compress(&mut state, &block);
+                filled = 0;
+            }
+        }
+    }
+
+    // Padding is a one bit, zeroes, and the message length in bits in the last 8 bytes
+    block[filled] = 0x80;
+    block[filled + 1..].fill(0);
+    if filled + 1 > block.len() - 8 {
+        compress(&mut state, &block);
+        block.fill(0);
+    }
+    block[56..].copy_from_slice(&(total_len * 8).to_be_bytes());
+    compress(&mut state, &block);
+
+    let mut hash = [0u8; 32];
+    for (bytes, word) in hash.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    hash
+}
+
+pub fn sha256(data: &[u8]) -> Hash {
+    sha256_parts(&[data])
+}
+
+pub fn leaf_hash(record: &[u8]) -> Hash {
+    sha256_parts(&[&[LEAF_PREFIX], record])
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    sha256_parts(&[&[NODE_PREFIX], left, right])
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+// The root of no leaves is the hash of nothing, so an empty log still has a root to compare
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return sha256(&[]);
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sibling {
+    Left(Hash),
+    Right(Hash),
+}
+
+// The siblings on the way from a leaf up to the root, lowest level first. Levels where the node
+// was carried up unpaired have no entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub siblings: Vec<Sibling>,
+}
+
+impl MerkleProof {
+    pub fn verify(&self, record: &[u8], root: &Hash) -> bool {
+        let hash = self
+            .siblings
+            .iter()
+            .fold(leaf_hash(record), |hash, sibling| match sibling {
+                Sibling::Left(left) => node_hash(left, &hash),
+                Sibling::Right(right) => node_hash(&hash, right),
+            });
+        hash == *root
+    }
+}
+
+pub fn proof(leaves: &[Hash], index: usize) -> MerkleProof {
+    assert!(
+        index < leaves.len(),
+        "Tried proving leaf {} of {}",
+        index,
+        leaves.len()
+    );
+
+    let mut siblings = Vec::new();
+    let mut level = leaves.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        if position % 2 == 1 {
+            siblings.push(Sibling::Left(level[position - 1]));
+        } else if position + 1 < level.len() {
+            siblings.push(Sibling::Right(level[position + 1]));
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    MerkleProof { index, siblings }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn hex(hash: &Hash) -> String {
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn sha256_known_digests() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Long enough that the length needs a block of its own
+        assert_eq!(
+            hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        let million = vec![b'a'; 1_000_000];
+        assert_eq!(
+            hex(&sha256(&million)),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn split_input_hashes_the_same() {
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        assert_eq!(sha256_parts(&[&data[..63], &data[63..]]), sha256(&data));
+    }
+
+    #[test]
+    fn proofs_verify_for_every_leaf() {
+        for n in 1..10 {
+            let records: Vec<Vec<u8>> = (0..n).map(|i| vec![i as u8; i + 1]).collect();
+            let leaves: Vec<Hash> = records.iter().map(|record| leaf_hash(record)).collect();
+            let root = root(&leaves);
+            for (i, record) in records.iter().enumerate() {
+                let proof = proof(&leaves, i);
+                assert!(proof.verify(record, &root));
+                assert!(!proof.verify(b"forged", &root));
+            }
+        }
+    }
+
+    #[test]
+    fn root_depends_on_order() {
+        let leaves = [leaf_hash(b"a"), leaf_hash(b"b"), leaf_hash(b"c")];
+        let swapped = [leaves[1], leaves[0], leaves[2]];
+        assert_ne!(root(&leaves), root(&swapped));
+        assert_eq!(root(&leaves[..1]), leaves[0]);
+        assert_eq!(root(&[]), sha256(b""));
+    }
+}
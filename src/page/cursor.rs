@@ -0,0 +1,310 @@
+use std::io::{self, ErrorKind};
+
+use super::Page;
+
+// Types that know how to lay themselves out in a page. Integers are written big-endian like
+// every other on-page header
+pub trait PageSerializable: Sized {
+    fn write_to(&self, writer: &mut PageWriter) -> Result<(), io::Error>;
+    fn read_from(reader: &mut PageReader) -> Result<Self, io::Error>;
+}
+
+pub struct PageWriter<'a> {
+    buf: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> PageWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        let end = self.position + bytes.len();
+        if end > self.buf.len() {
+            return Err(io::Error::new(
+                ErrorKind::WriteZero,
+                format!(
+                    "Tried writing {} bytes at {} with only {} available",
+                    bytes.len(),
+                    self.position,
+                    self.buf.len() - self.position
+                ),
+            ));
+        }
+        self.buf[self.position..end].copy_from_slice(bytes);
+        self.position = end;
+        Ok(())
+    }
+
+    pub fn put_u8(&mut self, value: u8) -> Result<(), io::Error> {
+        self.put_bytes(&[value])
+    }
+
+    pub fn put_u16(&mut self, value: u16) -> Result<(), io::Error> {
+        self.put_bytes(&value.to_be_bytes())
+    }
+
+    pub fn put_u32(&mut self, value: u32) -> Result<(), io::Error> {
+        self.put_bytes(&value.to_be_bytes())
+    }
+
+    pub fn put_u64(&mut self, value: u64) -> Result<(), io::Error> {
+        self.put_bytes(&value.to_be_bytes())
+    }
+
+    pub fn put_i32(&mut self, value: i32) -> Result<(), io::Error> {
+        self.put_bytes(&value.to_be_bytes())
+    }
+}
+
+// Carried by the UnexpectedEof error a PageReader returns instead of reading past the end of its
+// buffer, so parsers of untrusted pages can tell running out of page from other failures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnexpectedEndOfPage {
+    pub position: usize,
+    pub wanted: usize,
+    pub available: usize,
+}
+
+impl std::fmt::Display for UnexpectedEndOfPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Tried reading {} bytes at {} with only {} available",
+            self.wanted, self.position, self.available
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedEndOfPage {}
+
+pub struct PageReader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PageReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.position
+    }
+
+    // A length taken from the page itself can be anything, so this must not overflow either
+    pub fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        let Some(end) = self
+            .position
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len())
+        else {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                UnexpectedEndOfPage {
+                    position: self.position,
+                    wanted: len,
+                    available: self.remaining(),
+                },
+            ));
+        };
+        let bytes = &self.buf[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn get_array<const N: usize>(&mut self) -> Result<[u8; N], io::Error> {
+        Ok(self
+            .get_bytes(N)?
+            .try_into()
+            .expect("get_bytes returned the wrong length"))
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, io::Error> {
+        Ok(self.get_array::<1>()?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, io::Error> {
+        Ok(u16::from_be_bytes(self.get_array()?))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, io::Error> {
+        Ok(u32::from_be_bytes(self.get_array()?))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, io::Error> {
+        Ok(u64::from_be_bytes(self.get_array()?))
+    }
+
+    pub fn get_i32(&mut self) -> Result<i32, io::Error> {
+        Ok(i32::from_be_bytes(self.get_array()?))
+    }
+}
+
+impl Page {
+    // Returns the number of bytes the value took up
+    pub fn store<T: PageSerializable>(
+        &mut self,
+        offset: usize,
+        value: &T,
+    ) -> Result<usize, io::Error> {
+        let buf = self.mutate().get_mut(offset..).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Offset {} is outside the page", offset),
+            )
+        })?;
+        let mut writer = PageWriter::new(buf);
+        value.write_to(&mut writer)?;
+        Ok(writer.position())
+    }
+
+    pub fn load<T: PageSerializable>(&self, offset: usize) -> Result<T, io::Error> {
+        let buf = self.read().get(offset..).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Offset {} is outside the page", offset),
+            )
+        })?;
+        T::read_from(&mut PageReader::new(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const PAGESIZE: usize = 32;
+
+    #[derive(Debug, PartialEq)]
+    struct Account {
+        id: u32,
+        balance: i32,
+        name: Vec<u8>,
+    }
+
+    impl PageSerializable for Account {
+        fn write_to(&self, writer: &mut PageWriter) -> Result<(), io::Error> {
+            writer.put_u32(self.id)?;
+            writer.put_i32(self.balance)?;
+            writer.put_u8(self.name.len() as u8)?;
+            writer.put_bytes(&self.name)
+        }
+
+        fn read_from(reader: &mut PageReader) -> Result<Self, io::Error> {
+            let id = reader.get_u32()?;
+            let balance = reader.get_i32()?;
+            let len = reader.get_u8()? as usize;
+            let name = reader.get_bytes(len)?.to_vec();
+            Ok(Self { id, balance, name })
+        }
+    }
+
+    #[test]
+    fn store_and_load() {
+        let account = Account {
+            id: 7,
+            balance: -120,
+            name: b"alice".to_vec(),
+        };
+
+        let mut page = Page::new(PAGESIZE);
+        assert_eq!(page.store(4, &account).unwrap(), 14);
+        assert_eq!(&page.read()[4..8], &[0, 0, 0, 7]);
+        assert_eq!(page.load::<Account>(4).unwrap(), account);
+    }
+
+    #[test]
+    fn store_past_page_end() {
+        let account = Account {
+            id: 7,
+            balance: -120,
+            name: b"alice".to_vec(),
+        };
+
+        let mut page = Page::new(PAGESIZE);
+        let err = page.store(PAGESIZE - 10, &account).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert!(page.store(PAGESIZE + 1, &account).is_err());
+    }
+
+    fn end_of_page(err: &io::Error) -> &UnexpectedEndOfPage {
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        err.get_ref().unwrap().downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn read_up_to_page_end() {
+        let mut page = Page::new(PAGESIZE);
+        let mut writer = PageWriter::new(page.mutate());
+        writer.put_u64(1).unwrap();
+        writer.put_u32(2).unwrap();
+        writer.put_u16(3).unwrap();
+        writer.put_u8(4).unwrap();
+        writer.put_i32(-5).unwrap();
+        writer.put_bytes(&[6; 13]).unwrap();
+
+        let mut reader = PageReader::new(page.read());
+        assert_eq!(reader.get_u64().unwrap(), 1);
+        assert_eq!(reader.get_u32().unwrap(), 2);
+        assert_eq!(reader.get_u16().unwrap(), 3);
+        assert_eq!(reader.get_u8().unwrap(), 4);
+        assert_eq!(reader.get_i32().unwrap(), -5);
+        assert_eq!(reader.get_bytes(13).unwrap(), &[6; 13]);
+        assert_eq!(reader.position(), PAGESIZE);
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(reader.get_bytes(0).unwrap(), &[]);
+    }
+
+    #[test]
+    fn read_past_page_end() {
+        let page = Page::new(PAGESIZE);
+        let mut reader = PageReader::new(page.read());
+        reader.get_bytes(PAGESIZE - 3).unwrap();
+
+        let err = reader.get_u32().unwrap_err();
+        assert_eq!(
+            end_of_page(&err),
+            &UnexpectedEndOfPage {
+                position: PAGESIZE - 3,
+                wanted: 4,
+                available: 3,
+            }
+        );
+        // A failed read doesn't move the cursor
+        assert_eq!(reader.get_u16().unwrap(), 0);
+        for err in [
+            reader.get_u16().unwrap_err(),
+            reader.get_u64().unwrap_err(),
+            reader.get_i32().unwrap_err(),
+        ] {
+            assert_eq!(end_of_page(&err).available, 1);
+        }
+        assert_eq!(reader.get_u8().unwrap(), 0);
+        assert_eq!(end_of_page(&reader.get_u8().unwrap_err()).available, 0);
+
+        let err = PageReader::new(page.read())
+            .get_bytes(usize::MAX)
+            .unwrap_err();
+        assert_eq!(end_of_page(&err).wanted, usize::MAX);
+    }
+
+    #[test]
+    fn load_past_page_end() {
+        let mut page = Page::new(PAGESIZE);
+        page.mutate()[PAGESIZE - 2..].fill(1);
+
+        let err = page.load::<Account>(PAGESIZE - 2).unwrap_err();
+        assert_eq!(end_of_page(&err).position, 0);
+    }
+}
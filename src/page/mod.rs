@@ -1,157 +1,2780 @@
 use core::panic;
-use std::fs::{File, OpenOptions};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, ErrorKind, IoSliceMut, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use crate::framing;
+
+mod cursor;
+mod pool;
+mod storage;
+
+pub use cursor::{PageReader, PageSerializable, PageWriter, UnexpectedEndOfPage};
+pub use pool::{pooled_buffer_reuses, PooledPage};
+use storage::LostLock;
+pub use storage::{FileStorage, MemStorage, Storage};
+
+// An odd multiplier for Page::rolling_hashes, large enough that every byte of the window moves
+// the high bits
+const ROLLING_BASE: u64 = 0x100000001b3;
+
+#[derive(Clone, Debug)]
 pub struct Page {
-    data: Vec<u8>,
+    data: Box<[u8]>,
 }
 
 impl Page {
     pub fn new(page_size: usize) -> Self {
         Self {
-            data: vec![0; page_size],
+            data: vec![0; page_size].into_boxed_slice(),
         }
     }
 
-    pub fn from_vec(data: Vec<u8>, page_size: usize) -> Self {
+    pub fn from_vec(data: Vec<u8>, page_size: usize) -> Result<Self, PageError> {
+        Self::from_boxed_slice(data.into_boxed_slice(), page_size)
+    }
+
+    // Takes over an existing buffer as is, without copying it
+    pub fn from_boxed_slice(data: Box<[u8]>, page_size: usize) -> Result<Self, PageError> {
         if data.len() != page_size {
+            return Err(PageError::SizeMismatch {
+                expected: page_size,
+                got: data.len(),
+            });
+        }
+        Ok(Self { data })
+    }
+
+    pub fn read(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn mutate(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn check_range(&self, offset: usize, len: usize) {
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > self.data.len())
+        {
+            panic!(
+                "Tried accessing {} bytes at offset {} in page of size {}",
+                len,
+                offset,
+                self.data.len()
+            );
+        }
+    }
+
+    pub fn get_i32(&self, offset: usize) -> i32 {
+        self.check_range(offset, size_of::<i32>());
+        i32::from_be_bytes(
+            self.data[offset..offset + size_of::<i32>()]
+                .try_into()
+                .expect("Slice is too small"),
+        )
+    }
+
+    pub fn set_i32(&mut self, offset: usize, val: i32) {
+        self.check_range(offset, size_of::<i32>());
+        self.data[offset..offset + size_of::<i32>()].copy_from_slice(&val.to_be_bytes());
+    }
+
+    // Applies all writes or, if any of them doesn't fit in the page, none of them
+    pub fn write_fields(&mut self, writes: &[(usize, FieldValue)]) -> Result<(), PageError> {
+        for (offset, value) in writes {
+            let len = value.len();
+            if offset
+                .checked_add(len)
+                .is_none_or(|end| end > self.data.len())
+            {
+                return Err(PageError::OutOfBounds {
+                    offset: *offset,
+                    len,
+                    page_size: self.data.len(),
+                });
+            }
+        }
+        for (offset, value) in writes {
+            value.write_to(&mut self.data[*offset..*offset + value.len()]);
+        }
+        Ok(())
+    }
+
+    // Stored as a big-endian u16 byte length followed by the UTF-8 bytes. Returns how many
+    // bytes were used so fields can be packed one after another
+    pub fn set_string(&mut self, offset: usize, s: &str) -> Result<usize, io::Error> {
+        let page_size = self.data.len();
+        let buf = self.data.get_mut(offset..).ok_or(PageError::OutOfBounds {
+            offset,
+            len: 0,
+            page_size,
+        })?;
+        framing::write_frame(buf, s.as_bytes())
+    }
+
+    pub fn get_string(&self, offset: usize) -> Result<String, io::Error> {
+        let buf = self.data.get(offset..).ok_or(PageError::OutOfBounds {
+            offset,
+            len: 0,
+            page_size: self.data.len(),
+        })?;
+        let (bytes, _) = framing::read_frame(buf)?;
+        String::from_utf8(bytes.to_vec()).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    // For page size migrations. Keeps the common prefix, truncating or zero-padding the rest
+    pub fn resize_to(&self, new_size: usize) -> Page {
+        let mut page = Page::new(new_size);
+        let len = new_size.min(self.data.len());
+        page.data[..len].copy_from_slice(&self.data[..len]);
+        page
+    }
+
+    pub fn xor_into(&self, other: &Page, out: &mut Page) {
+        if self.data.len() != other.data.len() || self.data.len() != out.data.len() {
+            panic!(
+                "Tried xoring pages of size {} and {} into page of size {}",
+                self.data.len(),
+                other.data.len(),
+                out.data.len()
+            );
+        }
+        for ((out, a), b) in out.data.iter_mut().zip(&self.data).zip(&other.data) {
+            *out = a ^ b;
+        }
+    }
+
+    pub fn apply_delta(&mut self, delta: &Page) {
+        if self.data.len() != delta.data.len() {
+            panic!(
+                "Tried applying delta of size {} to page of size {}",
+                delta.data.len(),
+                self.data.len()
+            );
+        }
+        for (byte, delta) in self.data.iter_mut().zip(&delta.data) {
+            *byte ^= delta;
+        }
+    }
+
+    // Rotates left with wrap-around, for treating the page as a ring buffer
+    pub fn rotate(&mut self, n: usize) {
+        if !self.data.is_empty() {
+            let n = n % self.data.len();
+            self.data.rotate_left(n);
+        }
+    }
+
+    // The contiguous byte ranges where the two pages differ, in order
+    pub fn changed_ranges(&self, other: &Page) -> Vec<Range<usize>> {
+        if self.data.len() != other.data.len() {
+            panic!(
+                "Tried diffing pages of size {} and {}",
+                self.data.len(),
+                other.data.len()
+            );
+        }
+
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for (i, (a, b)) in self.data.iter().zip(&other.data).enumerate() {
+            match (a != b, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    ranges.push(s..i);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            ranges.push(s..self.data.len());
+        }
+        ranges
+    }
+
+    // Looks at every byte no matter where the first difference is, so the time taken doesn't
+    // leak how much of a secret matched
+    pub fn ct_eq(&self, other: &Page) -> bool {
+        if self.data.len() != other.data.len() {
+            return false;
+        }
+        let diff = self
+            .data
+            .iter()
+            .zip(&other.data)
+            .fold(0, |acc, (a, b)| acc | std::hint::black_box(a ^ b));
+        diff == 0
+    }
+
+    pub fn write_magic(&mut self, offset: usize, magic: &[u8]) {
+        self.check_range(offset, magic.len());
+        self.data[offset..offset + magic.len()].copy_from_slice(magic);
+    }
+
+    pub fn check_magic(&self, offset: usize, expected: &[u8]) -> bool {
+        let Some(end) = offset.checked_add(expected.len()) else {
+            return false;
+        };
+        self.data.get(offset..end) == Some(expected)
+    }
+
+    // The first CHECKSUM_LEN bytes hold a CRC32 of the rest of the page
+    pub fn compute_checksum(&self) -> u32 {
+        self.check_range(0, CHECKSUM_LEN);
+        crc32(&self.data[CHECKSUM_LEN..])
+    }
+
+    pub fn write_checksum(&mut self) {
+        let checksum = self.compute_checksum();
+        self.data[..CHECKSUM_LEN].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        checksum_matches(&self.data)
+    }
+
+    fn check_bit_index(&self, bit_index: usize) {
+        if bit_index >= self.data.len() * 8 {
             panic!(
-                "Tried initializing page with data size {} when page size is set to {}",
-                data.len(),
-                page_size
+                "Tried accessing bit {} in page of {} bits",
+                bit_index,
+                self.data.len() * 8
             );
         }
-        Self { data }
     }
 
-    pub fn read(&self) -> &Vec<u8> {
-        &self.data
-    }
+    pub fn get_bit(&self, bit_index: usize) -> bool {
+        self.check_bit_index(bit_index);
+        self.data[bit_index / 8] & (1 << (bit_index % 8)) != 0
+    }
+
+    pub fn set_bit(&mut self, bit_index: usize) {
+        self.check_bit_index(bit_index);
+        self.data[bit_index / 8] |= 1 << (bit_index % 8);
+    }
+
+    pub fn clear_bit(&mut self, bit_index: usize) {
+        self.check_bit_index(bit_index);
+        self.data[bit_index / 8] &= !(1 << (bit_index % 8));
+    }
+
+    pub fn first_clear_bit(&self) -> Option<usize> {
+        let byte_index = self.data.iter().position(|&byte| byte != u8::MAX)?;
+        Some(byte_index * 8 + self.data[byte_index].trailing_ones() as usize)
+    }
+
+    pub fn find_bytes(&self, needle: &[u8]) -> Option<usize> {
+        if needle.is_empty() {
+            return Some(0);
+        }
+        self.data
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    pub fn rfind_bytes(&self, needle: &[u8]) -> Option<usize> {
+        if needle.is_empty() {
+            return Some(self.data.len());
+        }
+        self.data
+            .windows(needle.len())
+            .rposition(|window| window == needle)
+    }
+
+    // Shannon entropy of the byte values in bits per byte, from 0 for a page of one repeated
+    // byte up to 8 for uniformly spread bytes. Pages near the top aren't worth compressing
+    pub fn byte_entropy(&self) -> f64 {
+        let mut histogram = [0usize; 256];
+        for &byte in self.data.iter() {
+            histogram[byte as usize] += 1;
+        }
+        let len = self.data.len() as f64;
+        histogram
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / len;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    // Polynomial hash of every window of window bytes, hashes[i] covering data[i..i + window].
+    // Equal windows hash equal wherever they sit in whichever page, so matching hashes point at
+    // candidates for shared runs. Arithmetic wraps, which makes the modulus 2^64
+    pub fn rolling_hashes(&self, window: usize) -> Vec<u64> {
+        assert!(window > 0, "Tried hashing windows of 0 bytes");
+        if window > self.data.len() {
+            return Vec::new();
+        }
+
+        // Weight of the byte leaving the window, ROLLING_BASE^(window - 1)
+        let outgoing = (1..window).fold(1u64, |power, _| power.wrapping_mul(ROLLING_BASE));
+        let mut hash = self.data[..window].iter().fold(0u64, |hash, &byte| {
+            hash.wrapping_mul(ROLLING_BASE).wrapping_add(byte as u64)
+        });
+        let mut hashes = Vec::with_capacity(self.data.len() - window + 1);
+        hashes.push(hash);
+        for (&old, &new) in self.data.iter().zip(&self.data[window..]) {
+            hash = hash
+                .wrapping_sub((old as u64).wrapping_mul(outgoing))
+                .wrapping_mul(ROLLING_BASE)
+                .wrapping_add(new as u64);
+            hashes.push(hash);
+        }
+        hashes
+    }
+
+    // The page as page_size / N elements of N bytes. Trailing bytes that don't fill a whole
+    // element are left out of the view
+    pub fn as_array<const N: usize>(&self) -> &[[u8; N]] {
+        let len = Self::array_len::<N>(self.data.len());
+        // [u8; N] has the size of N bytes and the alignment of u8, so the first len * N
+        // bytes of the page are exactly len arrays
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr().cast(), len) }
+    }
+
+    pub fn as_array_mut<const N: usize>(&mut self) -> &mut [[u8; N]] {
+        let len = Self::array_len::<N>(self.data.len());
+        // As in as_array, and the view borrows the page mutably for as long as it lives
+        unsafe { std::slice::from_raw_parts_mut(self.data.as_mut_ptr().cast(), len) }
+    }
+
+    fn array_len<const N: usize>(page_size: usize) -> usize {
+        assert!(N > 0, "Tried viewing a page as arrays of 0 bytes");
+        page_size / N
+    }
+}
+
+// A single big-endian field for Page::write_fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    I32(i32),
+    Bytes(Vec<u8>),
+}
+
+impl FieldValue {
+    pub fn len(&self) -> usize {
+        match self {
+            FieldValue::U8(_) => size_of::<u8>(),
+            FieldValue::U16(_) => size_of::<u16>(),
+            FieldValue::U32(_) => size_of::<u32>(),
+            FieldValue::U64(_) => size_of::<u64>(),
+            FieldValue::I32(_) => size_of::<i32>(),
+            FieldValue::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn write_to(&self, buf: &mut [u8]) {
+        match self {
+            FieldValue::U8(val) => buf.copy_from_slice(&val.to_be_bytes()),
+            FieldValue::U16(val) => buf.copy_from_slice(&val.to_be_bytes()),
+            FieldValue::U32(val) => buf.copy_from_slice(&val.to_be_bytes()),
+            FieldValue::U64(val) => buf.copy_from_slice(&val.to_be_bytes()),
+            FieldValue::I32(val) => buf.copy_from_slice(&val.to_be_bytes()),
+            FieldValue::Bytes(bytes) => buf.copy_from_slice(bytes),
+        }
+    }
+}
+
+// A private version of a page. Reads go to the shared page until the first mutation, which
+// copies the bytes and leaves the original untouched
+pub struct CowPage<'a> {
+    data: Cow<'a, [u8]>,
+}
+
+impl<'a> CowPage<'a> {
+    pub fn new(page: &'a Page) -> Self {
+        Self {
+            data: Cow::Borrowed(page.read()),
+        }
+    }
+
+    pub fn read(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn make_mut(&mut self) -> &mut [u8] {
+        self.data.to_mut()
+    }
+
+    pub fn is_copied(&self) -> bool {
+        matches!(self.data, Cow::Owned(_))
+    }
+
+    pub fn into_page(self) -> Page {
+        Page {
+            data: self.data.into_owned().into_boxed_slice(),
+        }
+    }
+}
+
+pub struct PageManager {
+    pub(crate) storage: Box<dyn Storage>,
+    pub page_size: usize,
+    // Only set when the manager opened the file itself
+    path: Option<PathBuf>,
+    write_hook: Option<Box<WriteHook>>,
+    read_cache: Option<ReadCache>,
+    disk_reads: usize,
+    write_buffer: Option<BTreeMap<usize, Page>>,
+    // Pages on disk while the cursor is known to sit at the end of the file. Anything that moves
+    // the cursor clears it. Code elsewhere in the crate seeking the pub(crate) storage has to
+    // clear it as well
+    eof_pages: Option<usize>,
+    bulk: Option<Vec<u8>>,
+    append_syscalls: usize,
+    checksums: bool,
+    reopen_on_stale: bool,
+    parity: Option<Box<Parity>>,
+    read_only: bool,
+}
+
+// Page g of the parity file is the XOR of data pages g * group_size up to (g + 1) * group_size,
+// with pages past the end counting as zeroes
+struct Parity {
+    pm: PageManager,
+    group_size: usize,
+}
+
+// A bulk session writes its appended pages out once this much has piled up
+const BULK_WRITE_BYTES: usize = 1 << 20;
+
+// Small LRU of recently read pages. The front of recency is the least recently used position
+struct ReadCache {
+    capacity: usize,
+    pages: HashMap<usize, Arc<Page>>,
+    recency: VecDeque<usize>,
+}
+
+impl ReadCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pages: HashMap::with_capacity(capacity),
+            recency: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn touch(&mut self, position: usize) {
+        self.recency.retain(|&p| p != position);
+        self.recency.push_back(position);
+    }
+
+    fn get(&mut self, position: usize) -> Option<&Arc<Page>> {
+        if !self.pages.contains_key(&position) {
+            return None;
+        }
+        self.touch(position);
+        self.pages.get(&position)
+    }
+
+    fn insert(&mut self, position: usize, page: Arc<Page>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.pages.contains_key(&position) && self.pages.len() == self.capacity {
+            if let Some(evicted) = self.recency.pop_front() {
+                self.pages.remove(&evicted);
+            }
+        }
+        self.pages.insert(position, page);
+        self.touch(position);
+    }
+
+    fn invalidate(&mut self, position: usize) {
+        if self.pages.remove(&position).is_some() {
+            self.recency.retain(|&p| p != position);
+        }
+    }
+
+    fn invalidate_from(&mut self, position: usize) {
+        self.pages.retain(|&p, _| p < position);
+        self.recency.retain(|&p| p < position);
+    }
+}
+
+const CHECKSUM_LEN: usize = 4;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn checksum_matches(bytes: &[u8]) -> bool {
+    match bytes.split_first_chunk::<CHECKSUM_LEN>() {
+        Some((stored, rest)) => u32::from_be_bytes(*stored) == crc32(rest),
+        None => false,
+    }
+}
+
+#[derive(Debug)]
+pub enum PageError {
+    SizeMismatch {
+        expected: usize,
+        got: usize,
+    },
+    OutOfBounds {
+        offset: usize,
+        len: usize,
+        page_size: usize,
+    },
+    Io(io::Error),
+}
+
+impl std::fmt::Display for PageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageError::SizeMismatch { expected, got } => write!(
+                f,
+                "Page has size {} when page size is set to {}",
+                got, expected
+            ),
+            PageError::OutOfBounds {
+                offset,
+                len,
+                page_size,
+            } => write!(
+                f,
+                "Tried accessing {} bytes at offset {} in page of size {}",
+                len, offset, page_size
+            ),
+            PageError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PageError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PageError {
+    fn from(err: io::Error) -> Self {
+        PageError::Io(err)
+    }
+}
+
+// Lets code that deals in io::Error keep using ? on page operations
+impl From<PageError> for io::Error {
+    fn from(err: PageError) -> Self {
+        match err {
+            PageError::Io(err) => err,
+            err => io::Error::new(ErrorKind::InvalidInput, err),
+        }
+    }
+}
+
+// Carried by the UnexpectedEof error read_page returns when the file ends partway through the
+// requested page, which is what a crash in the middle of append_page leaves behind
+#[derive(Debug)]
+pub struct ShortRead {
+    pub position: usize,
+    pub readable: usize,
+    pub page_size: usize,
+}
+
+impl std::fmt::Display for ShortRead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Page {} is torn, only {} of {} bytes could be read",
+            self.position, self.readable, self.page_size
+        )
+    }
+}
+
+impl std::error::Error for ShortRead {}
+
+// Returned by read_page_or_stale. A stale page is the last copy read successfully, the page may
+// have changed on disk since
+#[derive(Debug)]
+pub struct MaybeStale {
+    pub page: Page,
+    pub stale: bool,
+}
+
+// Called after every successful write with the position and the written page. The hook only
+// ever sees the page, so it must not try to reach back into the manager (e.g. through an
+// Rc<RefCell<PageManager>>), the manager is still mutably borrowed while it runs.
+pub type WriteHook = dyn FnMut(usize, &Page);
+
+impl PageManager {
+    // Takes an exclusive lock on the file, so a second manager opening it fails instead of the
+    // two overwriting each other's pages. Dropping the manager releases it
+    pub fn new(path: &str, page_size: usize) -> Result<Self, io::Error> {
+        let storage = FileStorage::open(path)?;
+        storage::try_lock(storage.file().expect("Just opened a file"), false)?;
+        let mut manager = Self::with_storage(Box::new(storage), page_size);
+        manager.path = Some(PathBuf::from(path));
+        Ok(manager)
+    }
+
+    // Takes a shared lock, so any number of readers can have the file open as long as no
+    // manager opened it with new. Every write fails with PermissionDenied
+    pub fn open_read_only(path: &str, page_size: usize) -> Result<Self, io::Error> {
+        let storage = FileStorage::open_read_only(path)?;
+        storage::try_lock(storage.file().expect("Just opened a file"), true)?;
+        let mut manager = Self::with_storage(Box::new(storage), page_size);
+        manager.path = Some(PathBuf::from(path));
+        manager.read_only = true;
+        Ok(manager)
+    }
+
+    pub fn with_storage(storage: Box<dyn Storage>, page_size: usize) -> Self {
+        Self {
+            storage,
+            page_size,
+            path: None,
+            write_hook: None,
+            read_cache: None,
+            disk_reads: 0,
+            write_buffer: None,
+            eof_pages: None,
+            bulk: None,
+            append_syscalls: 0,
+            checksums: false,
+            reopen_on_stale: false,
+            parity: None,
+            read_only: false,
+        }
+    }
+
+    // Keeps a parity page for every group_size pages in a file next to the data file, so any
+    // single page of a group can be rebuilt with repair_page. Every write reads the page it
+    // replaces to update the parity
+    pub fn with_parity(path: &str, page_size: usize, group_size: usize) -> Result<Self, io::Error> {
+        assert!(group_size > 0, "Tried using parity groups of 0 pages");
+        let parity = PageManager::new(&format!("{}.parity", path), page_size)?;
+        let mut manager = Self::new(path, page_size)?;
+        manager.parity = Some(Box::new(Parity {
+            pm: parity,
+            group_size,
+        }));
+        Ok(manager)
+    }
+
+    // Holds written pages in memory until flush, which writes each run of consecutive positions
+    // with a single write. Reads see the buffered pages
+    pub fn with_buffered_writes(mut self) -> Self {
+        self.write_buffer = Some(BTreeMap::new());
+        self
+    }
+
+    // Stamps a checksum into every page written and checks it on every page read from disk.
+    // The first 4 bytes of each page belong to the checksum from then on
+    pub fn with_checksums(mut self) -> Result<Self, io::Error> {
+        if self.page_size < CHECKSUM_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Page size {} leaves no room for a {} byte checksum",
+                    self.page_size, CHECKSUM_LEN
+                ),
+            ));
+        }
+        self.checksums = true;
+        Ok(self)
+    }
+
+    pub fn set_read_cache(&mut self, capacity: usize) {
+        self.read_cache = Some(ReadCache::new(capacity));
+    }
+
+    pub fn clear_read_cache(&mut self) {
+        self.read_cache = None;
+    }
+
+    pub fn set_write_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(usize, &Page) + 'static,
+    {
+        self.write_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_write_hook(&mut self) {
+        self.write_hook = None;
+    }
+
+    fn notify_write(&mut self, position: usize, page: &Page) {
+        if let Some(hook) = self.write_hook.as_mut() {
+            hook(position, page);
+        }
+    }
+}
+
+impl PageManager {
+    fn check_page_size(&self, page: &Page) -> Result<(), PageError> {
+        if page.read().len() != self.page_size {
+            return Err(PageError::SizeMismatch {
+                expected: self.page_size,
+                got: page.read().len(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn read_page(&mut self, position: usize) -> Result<Page, io::Error> {
+        self.read_page_shared(position).map(Arc::unwrap_or_clone)
+    }
+
+    // With the read cache on, every reader of a cached page shares the cache's copy
+    pub fn read_page_shared(&mut self, position: usize) -> Result<Arc<Page>, io::Error> {
+        if let Some(page) = self
+            .write_buffer
+            .as_ref()
+            .and_then(|buffer| buffer.get(&position))
+        {
+            return Ok(Arc::new(page.clone()));
+        }
+        if let Some(page) = self
+            .read_cache
+            .as_mut()
+            .and_then(|cache| cache.get(position))
+        {
+            return Ok(Arc::clone(page));
+        }
+
+        let page = Arc::new(self.read_page_from_disk(position)?);
+        if let Some(cache) = self.read_cache.as_mut() {
+            cache.insert(position, Arc::clone(&page));
+        }
+        Ok(page)
+    }
+
+    // Always goes to storage, but if that fails with an error that may go away by itself, hands
+    // out the copy in the read cache instead, marked stale. For callers that would rather show
+    // old data than none
+    pub fn read_page_or_stale(&mut self, position: usize) -> Result<MaybeStale, io::Error> {
+        if let Some(page) = self
+            .write_buffer
+            .as_ref()
+            .and_then(|buffer| buffer.get(&position))
+        {
+            return Ok(MaybeStale {
+                page: page.clone(),
+                stale: false,
+            });
+        }
+
+        match self.read_page_from_disk(position) {
+            Ok(page) => {
+                if let Some(cache) = self.read_cache.as_mut() {
+                    cache.insert(position, Arc::new(page.clone()));
+                }
+                Ok(MaybeStale { page, stale: false })
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                let cached = self
+                    .read_cache
+                    .as_mut()
+                    .and_then(|cache| cache.get(position));
+                match cached {
+                    Some(page) => Ok(MaybeStale {
+                        page: Page::clone(page),
+                        stale: true,
+                    }),
+                    None => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_page_from_disk(&mut self, position: usize) -> Result<Page, io::Error> {
+        let mut buf = vec![0; self.page_size];
+        self.read_from_disk_into(position, &mut buf)?;
+        Ok(Page {
+            data: buf.into_boxed_slice(),
+        })
+    }
+
+    fn read_from_disk_into(&mut self, position: usize, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.reopen_on_stale(|pm| pm.read_from_storage_into(position, buf))
+    }
+
+    fn read_from_storage_into(&mut self, position: usize, buf: &mut [u8]) -> Result<(), io::Error> {
+        let offset = (position * self.page_size)
+            .try_into()
+            .expect("usize couldn't be converted into u64");
+
+        self.flush_bulk()?;
+        self.seek(SeekFrom::Start(offset))?;
+        let mut readable = 0;
+        while readable < buf.len() {
+            match self.storage.read(&mut buf[readable..]) {
+                Ok(0) => break,
+                Ok(n) => readable += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.disk_reads += 1;
+
+        if readable == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Page {} is past the end of the file", position),
+            ));
+        }
+        if readable < self.page_size {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                ShortRead {
+                    position,
+                    readable,
+                    page_size: self.page_size,
+                },
+            ));
+        }
+        if self.checksums && !checksum_matches(buf) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Checksum mismatch in page {}", position),
+            ));
+        }
+        Ok(())
+    }
+
+    fn stamp_checksum<'a>(&self, page: &'a Page) -> Cow<'a, Page> {
+        if !self.checksums {
+            return Cow::Borrowed(page);
+        }
+        let mut stamped = page.clone();
+        stamped.write_checksum();
+        Cow::Owned(stamped)
+    }
+
+    // Reads into a buffer recycled from earlier pooled reads on this thread. Meant for scans,
+    // so pages read this way don't go into the read cache
+    pub fn read_page_pooled(&mut self, position: usize) -> Result<PooledPage, io::Error> {
+        let mut page = PooledPage::take(self.page_size);
+        self.read_page_into(position, &mut page)?;
+        Ok(page)
+    }
+
+    // Refills a page the caller already owns, so a scan can get by with a single allocation.
+    // Like pooled reads this bypasses the read cache
+    pub fn read_page_into(&mut self, position: usize, buf: &mut Page) -> Result<(), io::Error> {
+        assert_eq!(
+            buf.read().len(),
+            self.page_size,
+            "Tried reading into page of size {} when page size is set to {}",
+            buf.read().len(),
+            self.page_size
+        );
+        let cached = self
+            .write_buffer
+            .as_ref()
+            .and_then(|buffer| buffer.get(&position))
+            .or_else(|| {
+                self.read_cache
+                    .as_mut()
+                    .and_then(|cache| cache.get(position))
+                    .map(|page| page.as_ref())
+            });
+        match cached {
+            Some(cached) => buf.mutate().copy_from_slice(cached.read()),
+            None => self.read_from_disk_into(position, buf.mutate())?,
+        }
+        Ok(())
+    }
+
+    // Reads the page at positions[i] into out[i]. Each run of consecutive positions is read with
+    // a single vectored read straight into its pages. Like read_page_into this bypasses the read
+    // cache, but pages that are buffered or cached are copied from there
+    pub fn read_pages_scatter(
+        &mut self,
+        positions: &[usize],
+        out: &mut [Page],
+    ) -> Result<(), io::Error> {
+        if positions.len() != out.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Tried reading {} pages into {} output pages",
+                    positions.len(),
+                    out.len()
+                ),
+            ));
+        }
+        if let Some(page) = out.iter().find(|page| page.read().len() != self.page_size) {
+            return Err(PageError::SizeMismatch {
+                expected: self.page_size,
+                got: page.read().len(),
+            }
+            .into());
+        }
+
+        let mut start = 0;
+        while start < positions.len() {
+            let first = positions[start];
+            let len = positions[start..]
+                .iter()
+                .enumerate()
+                .take_while(|&(i, &position)| position == first + i)
+                .count();
+            let run = &mut out[start..start + len];
+            start += len;
+
+            let in_memory = (first..first + len).any(|position| {
+                self.write_buffer
+                    .as_ref()
+                    .is_some_and(|buffer| buffer.contains_key(&position))
+                    || self
+                        .read_cache
+                        .as_ref()
+                        .is_some_and(|cache| cache.pages.contains_key(&position))
+            });
+            if in_memory {
+                for (position, page) in (first..).zip(run.iter_mut()) {
+                    self.read_page_into(position, page)?;
+                }
+                continue;
+            }
+            self.reopen_on_stale(|pm| pm.read_run_vectored(first, run))?;
+        }
+        Ok(())
+    }
+
+    fn read_run_vectored(&mut self, first: usize, pages: &mut [Page]) -> Result<(), io::Error> {
+        let offset = (first * self.page_size)
+            .try_into()
+            .expect("usize couldn't be converted into u64");
+        self.flush_bulk()?;
+        self.seek(SeekFrom::Start(offset))?;
+
+        let mut slices: Vec<IoSliceMut> = pages
+            .iter_mut()
+            .map(|page| IoSliceMut::new(page.mutate()))
+            .collect();
+        let mut remaining = &mut slices[..];
+        let mut readable = 0;
+        while !remaining.is_empty() {
+            match self.storage.read_vectored(remaining) {
+                Ok(0) => break,
+                Ok(n) => {
+                    readable += n;
+                    IoSliceMut::advance_slices(&mut remaining, n);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.disk_reads += 1;
+
+        // Report the first page that didn't come back whole the way a single read would
+        let position = first + readable / self.page_size;
+        if readable % self.page_size != 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                ShortRead {
+                    position,
+                    readable: readable % self.page_size,
+                    page_size: self.page_size,
+                },
+            ));
+        }
+        if position < first + pages.len() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Page {} is past the end of the file", position),
+            ));
+        }
+        if self.checksums {
+            if let Some(i) = pages.iter().position(|page| !page.verify_checksum()) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Checksum mismatch in page {}", first + i),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), io::Error> {
+        if self.read_only {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Tried writing through a read-only PageManager",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn write_page(&mut self, position: usize, page: &Page) -> Result<(), PageError> {
+        self.check_writable()?;
+        self.check_page_size(page)?;
+        let page = &*self.stamp_checksum(page);
+        if self.parity.is_some() {
+            let old = self.read_page_or_zeroes(position)?;
+            self.update_parity(position, Some(&old), page)?;
+        }
+        if let Some(cache) = self.read_cache.as_mut() {
+            cache.invalidate(position);
+        }
+        if let Some(buffer) = self.write_buffer.as_mut() {
+            buffer.insert(position, page.clone());
+            return Ok(());
+        }
+        self.flush_bulk()?;
+        let offset = (position * self.page_size)
+            .try_into()
+            .expect("usize couldn't be converted into u64");
+        self.write_at(offset, page.read())?;
+
+        self.notify_write(position, page);
+        Ok(())
+    }
+
+    // Overwrites part of the page at position. Anything that needs to see whole pages (buffered
+    // writes, checksums, a write hook, parity) gets a read-modify-write of the full page instead
+    pub fn write_bytes(
+        &mut self,
+        position: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), PageError> {
+        self.check_writable()?;
+        if offset
+            .checked_add(bytes.len())
+            .is_none_or(|end| end > self.page_size)
+        {
+            return Err(PageError::OutOfBounds {
+                offset,
+                len: bytes.len(),
+                page_size: self.page_size,
+            });
+        }
+        if self.write_buffer.is_some()
+            || self.checksums
+            || self.write_hook.is_some()
+            || self.parity.is_some()
+        {
+            let mut page = self.read_page(position)?;
+            page.mutate()[offset..offset + bytes.len()].copy_from_slice(bytes);
+            return self.write_page(position, &page);
+        }
+
+        if let Some(cache) = self.read_cache.as_mut() {
+            cache.invalidate(position);
+        }
+        self.flush_bulk()?;
+        self.write_at((position * self.page_size + offset) as u64, bytes)?;
+        Ok(())
+    }
+
+    pub fn append_page(&mut self, page: &Page) -> Result<usize, PageError> {
+        self.check_writable()?;
+        self.check_page_size(page)?;
+        if self.write_buffer.is_some() {
+            let new_page_position = self.n_pages()?;
+            self.write_page(new_page_position, page)?;
+            return Ok(new_page_position);
+        }
+        let page = &*self.stamp_checksum(page);
+        if self.bulk.is_some() {
+            let on_disk = self.seek_to_end()?;
+            let bulk = self.bulk.as_mut().expect("Checked above");
+            let new_page_position = on_disk + bulk.len() / self.page_size;
+            bulk.extend_from_slice(page.read());
+            if bulk.len() >= BULK_WRITE_BYTES {
+                self.flush_bulk()?;
+            }
+            if let Some(cache) = self.read_cache.as_mut() {
+                cache.invalidate(new_page_position);
+            }
+            self.update_parity(new_page_position, None, page)?;
+            return Ok(new_page_position);
+        }
+
+        let new_page_position = self.reopen_on_stale(|pm| pm.write_at_end(page))?;
+        self.update_parity(new_page_position, None, page)?;
+        if let Some(cache) = self.read_cache.as_mut() {
+            cache.invalidate(new_page_position);
+        }
+        self.notify_write(new_page_position, page);
+        Ok(new_page_position)
+    }
+
+    // Pages past the end of the file read as zeroes, like holes do
+    fn read_page_or_zeroes(&mut self, position: usize) -> Result<Page, io::Error> {
+        match self.read_page(position) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(Page::new(self.page_size)),
+            result => result,
+        }
+    }
+
+    // Folds replacing old (zeroes if None) with new at position into its group's parity page
+    fn update_parity(
+        &mut self,
+        position: usize,
+        old: Option<&Page>,
+        new: &Page,
+    ) -> Result<(), PageError> {
+        let Some(parity) = self.parity.as_mut() else {
+            return Ok(());
+        };
+        let group = position / parity.group_size;
+        let mut page = parity.pm.read_page_or_zeroes(group)?;
+        for (i, byte) in page.mutate().iter_mut().enumerate() {
+            *byte ^= old.map_or(0, |old| old.read()[i]) ^ new.read()[i];
+        }
+        parity.pm.write_page(group, &page)
+    }
+
+    // XOR of the parity page and every other page in the group, which is what the page at
+    // position has to be
+    fn rebuild_from_parity(
+        &mut self,
+        parity: &mut Parity,
+        position: usize,
+    ) -> Result<Page, io::Error> {
+        let group = position / parity.group_size;
+        let mut page = parity.pm.read_page_or_zeroes(group)?;
+        let first = group * parity.group_size;
+        for member in (first..first + parity.group_size).filter(|&member| member != position) {
+            let member = self.read_page_or_zeroes(member)?;
+            for (byte, other) in page.mutate().iter_mut().zip(member.read()) {
+                *byte ^= other;
+            }
+        }
+        Ok(page)
+    }
+
+    fn take_parity(&mut self) -> Result<Box<Parity>, io::Error> {
+        self.parity.take().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::Unsupported,
+                "Only a manager opened with parity can check or repair pages",
+            )
+        })
+    }
+
+    // Whether the page at position matches what its group's parity says it should be
+    pub fn verify_parity(&mut self, position: usize) -> Result<bool, io::Error> {
+        let mut parity = self.take_parity()?;
+        let verified = self
+            .rebuild_from_parity(&mut parity, position)
+            .and_then(|expected| Ok(self.read_page_or_zeroes(position)?.read() == expected.read()));
+        self.parity = Some(parity);
+        verified
+    }
+
+    // Rebuilds the page at position from parity and writes it back. This only works as long as
+    // every other page in the group is intact
+    pub fn repair_page(&mut self, position: usize) -> Result<Page, PageError> {
+        let mut parity = self.take_parity()?;
+        // The parity already describes the repaired page, so the write mustn't fold in the broken
+        // one it replaces
+        let repaired = self
+            .rebuild_from_parity(&mut parity, position)
+            .map_err(PageError::from)
+            .and_then(|page| self.write_page(position, &page).map(|_| page));
+        self.parity = Some(parity);
+        repaired
+    }
+
+    fn write_at_end(&mut self, page: &Page) -> Result<usize, io::Error> {
+        let position = self.seek_to_end()?;
+        self.append_syscalls += 1;
+        if let Err(err) = self.storage.write_all(page.read()) {
+            self.eof_pages = None;
+            return Err(err);
+        }
+        self.eof_pages = Some(position + 1);
+        Ok(position)
+    }
+
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), io::Error> {
+        self.reopen_on_stale(|pm| {
+            pm.seek(SeekFrom::Start(offset))?;
+            pm.storage.write_all(bytes)
+        })
+    }
+
+    // Opens the file at the stored path again whenever the handle turns out to be stale, e.g.
+    // because the file was replaced underneath us, and retries the operation once on the new
+    // handle. Only for managers that opened the file themselves
+    pub fn with_reopen_on_stale(mut self) -> Self {
+        self.reopen_on_stale = true;
+        self
+    }
+
+    fn reopen_on_stale<T>(
+        &mut self,
+        mut op: impl FnMut(&mut Self) -> Result<T, io::Error>,
+    ) -> Result<T, io::Error> {
+        match op(self) {
+            Err(err) if self.reopen_on_stale && self.path.is_some() && is_stale(&err) => {
+                let path = self.path.as_ref().expect("Checked above");
+                let storage = if self.read_only {
+                    FileStorage::open_read_only(path)?
+                } else {
+                    FileStorage::open(path)?
+                };
+                // Locked only once the old handle is gone, its lock would stand in the way. If
+                // someone else got the file in between, the manager is left failing every access
+                // rather than writing to a file it holds no lock on
+                self.storage = Box::new(LostLock);
+                self.eof_pages = None;
+                storage::try_lock(storage.file().expect("Just opened a file"), self.read_only)?;
+                self.storage = Box::new(storage);
+                op(self)
+            }
+            result => result,
+        }
+    }
+
+    // Appends made until end_bulk are collected and written to the end of the file in large
+    // chunks instead of one write per page. Reads and writes of other pages still work in
+    // between, they just push out what has been collected so far first
+    pub fn begin_bulk(&mut self) {
+        self.bulk.get_or_insert_with(Default::default);
+    }
+
+    pub fn end_bulk(&mut self) -> Result<(), io::Error> {
+        self.flush_bulk()?;
+        self.bulk = None;
+        Ok(())
+    }
+
+    fn flush_bulk(&mut self) -> Result<(), io::Error> {
+        let Some(bulk) = self.bulk.as_mut().filter(|bulk| !bulk.is_empty()) else {
+            return Ok(());
+        };
+        let mut bytes = std::mem::take(bulk);
+
+        let written = self.seek_to_end().and_then(|start| {
+            self.append_syscalls += 1;
+            self.storage.write_all(&bytes).map(|_| start)
+        });
+        let start = match written {
+            Ok(start) => start,
+            Err(err) => {
+                self.eof_pages = None;
+                self.bulk = Some(bytes);
+                return Err(err);
+            }
+        };
+        self.eof_pages = Some(start + bytes.len() / self.page_size);
+
+        if self.write_hook.is_some() {
+            for (i, chunk) in bytes.chunks(self.page_size).enumerate() {
+                let page = Page { data: chunk.into() };
+                self.notify_write(start + i, &page);
+            }
+        }
+
+        // Hand the allocation back for the rest of the session
+        bytes.clear();
+        self.bulk = Some(bytes);
+        Ok(())
+    }
+
+    // Moves the cursor to the end of the file and returns how many pages are on disk, unless the
+    // cursor is known to already be there
+    fn seek_to_end(&mut self) -> Result<usize, io::Error> {
+        if let Some(on_disk) = self.eof_pages {
+            return Ok(on_disk);
+        }
+        self.append_syscalls += 1;
+        let filesize = self.storage.seek(SeekFrom::End(0))?;
+        let on_disk = filesize as usize / self.page_size;
+        self.eof_pages = Some(on_disk);
+        Ok(on_disk)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
+        self.eof_pages = None;
+        self.storage.seek(pos)
+    }
+
+    pub fn iter_with_positions(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(usize, Page), io::Error>> + '_ {
+        let (n_pages, err) = match self.n_pages() {
+            Ok(n_pages) => (n_pages, None),
+            Err(err) => (0, Some(err)),
+        };
+        err.map(Err).into_iter().chain(
+            (0..n_pages).map(move |position| self.read_page(position).map(|page| (position, page))),
+        )
+    }
+
+    // Streams count pages starting at start without loading them all at once. The reader gets its
+    // own handle on the file, a try_clone would share the cursor with this manager
+    pub fn range_reader(&mut self, start: usize, count: usize) -> Result<Box<dyn Read>, io::Error> {
+        self.flush()?;
+        let offset = (start * self.page_size) as u64;
+        let len = (count * self.page_size) as u64;
+        match &self.path {
+            Some(path) => {
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file.take(len)))
+            }
+            // Nothing to open a second handle on, so hand out a copy of the range instead
+            None => {
+                let mut buf = Vec::new();
+                self.seek(SeekFrom::Start(offset))?;
+                Read::by_ref(&mut self.storage)
+                    .take(len)
+                    .read_to_end(&mut buf)?;
+                Ok(Box::new(io::Cursor::new(buf)))
+            }
+        }
+    }
+
+    pub fn prefetch_range(&mut self, start: usize, count: usize) -> Result<(), io::Error> {
+        let offset = start * self.page_size;
+
+        #[cfg(target_os = "linux")]
+        if let Some(file) = self.storage.file() {
+            use std::os::fd::AsRawFd;
+
+            let len = count * self.page_size;
+            let result = unsafe {
+                libc::posix_fadvise(
+                    file.as_raw_fd(),
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                    libc::POSIX_FADV_WILLNEED,
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::from_raw_os_error(result));
+            }
+        }
+
+        // No readahead advice here, so pull the pages through the OS cache by reading them
+        #[cfg(not(target_os = "linux"))]
+        {
+            let end = (start + count).min(self.n_pages()?);
+            let mut buf = vec![0; self.page_size];
+            self.seek(SeekFrom::Start(offset as u64))?;
+            for _ in start..end {
+                self.storage.read_exact(&mut buf)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Packs the live pages to the front of the file in position order and cuts off the rest.
+    // Returns (old, new) positions for every live page so callers can fix up references
+    pub fn defragment(&mut self, live: &[usize]) -> Result<Vec<(usize, usize)>, io::Error> {
+        self.check_writable()?;
+        let mut live = live.to_vec();
+        live.sort_unstable();
+        live.dedup();
+
+        let mut remap = Vec::with_capacity(live.len());
+        for (new_position, &old_position) in live.iter().enumerate() {
+            if old_position != new_position {
+                let page = self.read_page(old_position)?;
+                self.write_page(new_position, &page)?;
+            }
+            remap.push((old_position, new_position));
+        }
+
+        self.flush()?;
+        // Parity counts pages past the end as zeroes, so the cut off pages have to leave their
+        // groups first
+        if self.parity.is_some() {
+            let zeroes = Page::new(self.page_size);
+            for position in live.len()..self.n_pages()? {
+                let old = self.read_page(position)?;
+                self.update_parity(position, Some(&old), &zeroes)?;
+            }
+        }
+        self.eof_pages = None;
+        self.storage.set_len((live.len() * self.page_size) as u64)?;
+        if let Some(cache) = self.read_cache.as_mut() {
+            cache.invalidate_from(live.len());
+        }
+        Ok(remap)
+    }
+
+    // Writes only reach the OS, nothing here syncs on its own. Whoever needs a write to survive a
+    // crash (a log flush, a checkpoint) has to call one of these before relying on it. sync_data
+    // skips metadata that isn't needed to read the data back, like timestamps
+    pub fn sync(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        if let Some(parity) = self.parity.as_mut() {
+            parity.pm.sync()?;
+        }
+        self.storage.sync_all()
+    }
+
+    pub fn sync_data(&mut self) -> Result<(), io::Error> {
+        self.flush()?;
+        if let Some(parity) = self.parity.as_mut() {
+            parity.pm.sync_data()?;
+        }
+        self.storage.sync_data()
+    }
+
+    // Atomically moves the file to final_path once everything written so far is durable. The
+    // directory has to be synced too, otherwise the rename itself can be lost in a crash
+    pub fn durable_publish(&mut self, final_path: &str) -> Result<(), io::Error> {
+        let final_path = PathBuf::from(final_path);
+        let Some(path) = self.path.clone() else {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Only a manager that opened its own file can publish it",
+            ));
+        };
+        self.check_writable()?;
+
+        self.sync()?;
+        fs::rename(&path, &final_path)?;
+
+        #[cfg(unix)]
+        {
+            let final_dir = parent_dir(&final_path);
+            File::open(final_dir)?.sync_all()?;
+            let old_dir = parent_dir(&path);
+            if old_dir != final_dir {
+                File::open(old_dir)?.sync_all()?;
+            }
+        }
+
+        self.path = Some(final_path);
+        Ok(())
+    }
+
+    // FNV-1a over the whole file, streamed a page at a time so memory use doesn't grow with the
+    // file. Two copies of a database can be compared by exchanging just this value
+    pub fn file_digest(&mut self) -> Result<u64, io::Error> {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        self.flush()?;
+        self.seek(SeekFrom::Start(0))?;
+
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut buf = vec![0; self.page_size];
+        loop {
+            let n = match self.storage.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            for &byte in &buf[..n] {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+        Ok(hash)
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.flush_bulk()?;
+        let Some(buffer) = self.write_buffer.as_mut() else {
+            return Ok(());
+        };
+        let pages = std::mem::take(buffer);
+
+        let mut pages = pages.into_iter().peekable();
+        while let Some((start, page)) = pages.next() {
+            let mut run = vec![(start, page)];
+            while let Some(next) = pages.next_if(|(position, _)| *position == start + run.len()) {
+                run.push(next);
+            }
+
+            let bytes: Vec<u8> = run
+                .iter()
+                .flat_map(|(_, page)| page.read().iter().copied())
+                .collect();
+            let offset = (start * self.page_size)
+                .try_into()
+                .expect("usize couldn't be converted into u64");
+            let written = self.write_at(offset, &bytes);
+
+            // Keep whatever didn't make it to disk so a later flush can try again
+            if let Err(err) = written {
+                let buffer = self.write_buffer.get_or_insert_with(Default::default);
+                buffer.extend(run);
+                buffer.extend(pages);
+                return Err(err);
+            }
+
+            for (position, page) in &run {
+                self.notify_write(*position, page);
+            }
+        }
+        Ok(())
+    }
+
+    // Copies the file to dest without filling in holes, so a mostly preallocated database stays
+    // small. On Linux only the allocated regions reported by SEEK_DATA/SEEK_HOLE are copied,
+    // anywhere else (or without a file underneath) pages that are all zeroes are left out
+    pub fn sparse_copy(&mut self, dest: &str) -> Result<(), io::Error> {
+        self.flush()?;
+        let len = self.storage.size()?;
+
+        #[cfg(target_os = "linux")]
+        if let Some(file) = self.storage.file() {
+            self.eof_pages = None;
+            return copy_data_extents(file, len, dest, self.page_size);
+        }
+
+        let mut dest = File::create(dest)?;
+        let mut buf = vec![0; self.page_size];
+        self.seek(SeekFrom::Start(0))?;
+        for position in 0..self.n_pages()? {
+            self.storage.read_exact(&mut buf)?;
+            if buf.iter().any(|&byte| byte != 0) {
+                dest.seek(SeekFrom::Start((position * self.page_size) as u64))?;
+                dest.write_all(&buf)?;
+            }
+        }
+
+        dest.set_len(len)?;
+        dest.sync_all()
+    }
+
+    pub fn n_pages(&self) -> Result<usize, io::Error> {
+        let filesize = self.storage.size()?;
+
+        assert!(filesize as usize % self.page_size == 0);
+        let on_disk = filesize as usize / self.page_size;
+        let buffered = self
+            .write_buffer
+            .as_ref()
+            .and_then(|buffer| buffer.last_key_value())
+            .map_or(0, |(&position, _)| position + 1);
+        let bulk = self
+            .bulk
+            .as_ref()
+            .map_or(0, |bulk| bulk.len() / self.page_size);
+        Ok((on_disk + bulk).max(buffered))
+    }
+}
+
+impl Drop for PageManager {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn copy_data_extents(file: &File, len: u64, dest: &str, chunk: usize) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::FileExt;
+
+    let dest = File::create(dest)?;
+    let fd = file.as_raw_fd();
+
+    let mut position = 0;
+    let mut buf = vec![0; chunk];
+    while position < len {
+        let data = unsafe { libc::lseek(fd, position as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            // ENXIO means there is no data left after position, only a trailing hole
+            if err.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(err);
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (mut offset, end) = (data as u64, hole as u64);
+        while offset < end {
+            let n = buf.len().min((end - offset) as usize);
+            file.read_exact_at(&mut buf[..n], offset)?;
+            dest.write_all_at(&buf[..n], offset)?;
+            offset += n as u64;
+        }
+        position = end;
+    }
+
+    // Extending the length keeps any trailing hole a hole
+    dest.set_len(len)?;
+    dest.sync_all()
+}
+
+#[cfg(unix)]
+fn parent_dir(path: &std::path::Path) -> &std::path::Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    }
+}
+
+// ESTALE, which only has an ErrorKind of its own on newer toolchains
+#[cfg(target_os = "linux")]
+fn is_stale(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ESTALE)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_stale(_err: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    const PAGESIZE: usize = 32;
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::fs::OpenOptions;
+    use std::rc::Rc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn page_init() {
+        let page = Page::new(PAGESIZE);
+        assert!(page.data.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn page_from_vec() {
+        let page = Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap();
+        assert!(page.data.iter().all(|&byte| byte == 1));
+    }
+
+    #[test]
+    fn page_from_too_large_vec() {
+        let err = Page::from_vec(vec![1; PAGESIZE * 2], PAGESIZE).unwrap_err();
+        assert!(matches!(
+            err,
+            PageError::SizeMismatch {
+                expected: PAGESIZE,
+                got
+            } if got == PAGESIZE * 2
+        ));
+    }
+
+    #[test]
+    fn page_from_too_small_vec() {
+        let err = Page::from_vec(vec![1; PAGESIZE - 1], PAGESIZE).unwrap_err();
+        assert!(matches!(
+            err,
+            PageError::SizeMismatch {
+                expected: PAGESIZE,
+                got
+            } if got == PAGESIZE - 1
+        ));
+    }
+
+    #[test]
+    fn page_from_boxed_slice() {
+        let data = vec![4; PAGESIZE].into_boxed_slice();
+        let ptr = data.as_ptr();
+        let page = Page::from_boxed_slice(data, PAGESIZE).unwrap();
+        assert!(page.read().iter().all(|&byte| byte == 4));
+        assert_eq!(page.read().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn page_from_wrong_boxed_slice() {
+        let err =
+            Page::from_boxed_slice(vec![4; PAGESIZE - 1].into_boxed_slice(), PAGESIZE).unwrap_err();
+        assert!(matches!(err, PageError::SizeMismatch { .. }));
+        assert!(
+            Page::from_boxed_slice(vec![4; PAGESIZE + 1].into_boxed_slice(), PAGESIZE).is_err()
+        );
+    }
+
+    #[test]
+    fn page_read() {
+        let page = Page::from_vec(vec![2; PAGESIZE], PAGESIZE).unwrap();
+        assert!(page.read().iter().all(|&byte| byte == 2));
+    }
+
+    #[test]
+    fn page_mutate() {
+        let mut mutable_page = Page::new(PAGESIZE);
+        mutable_page.mutate().fill(2);
+        assert!(mutable_page.read().iter().all(|&byte| byte == 2));
+    }
+
+    #[test]
+    fn page_i32_round_trip() {
+        let mut page = Page::new(PAGESIZE);
+        for offset in [0, PAGESIZE - 4] {
+            for val in [0, 1, 123456, -1, -987654, i32::MAX, i32::MIN] {
+                page.set_i32(offset, val);
+                assert_eq!(page.get_i32(offset), val);
+            }
+        }
+
+        page.set_i32(0, -2);
+        assert_eq!(&page.read()[..4], &[0xFF, 0xFF, 0xFF, 0xFE]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_get_i32_out_of_bounds() {
+        Page::new(PAGESIZE).get_i32(PAGESIZE - 3);
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_set_i32_out_of_bounds() {
+        Page::new(PAGESIZE).set_i32(usize::MAX, 1);
+    }
+
+    #[test]
+    fn page_string_round_trip() {
+        let mut page = Page::new(PAGESIZE);
+        let used = page.set_string(0, "hello").unwrap();
+        assert_eq!(used, 7);
+        let used = used + page.set_string(used, "").unwrap();
+        page.set_string(used, "wörld").unwrap();
+
+        assert_eq!(page.get_string(0).unwrap(), "hello");
+        assert_eq!(page.get_string(7).unwrap(), "");
+        assert_eq!(page.get_string(9).unwrap(), "wörld");
+        assert_eq!(&page.read()[..3], &[0, 5, b'h']);
+    }
+
+    #[test]
+    fn page_string_must_fit() {
+        let mut page = Page::new(PAGESIZE);
+        let exact = "x".repeat(PAGESIZE - 2);
+        assert_eq!(page.set_string(0, &exact).unwrap(), PAGESIZE);
+        assert_eq!(page.get_string(0).unwrap(), exact);
+
+        assert!(page.set_string(1, &exact).is_err());
+        assert!(page.set_string(PAGESIZE + 1, "").is_err());
+    }
+
+    #[test]
+    fn page_get_string_rejects_bad_data() {
+        let mut page = Page::new(PAGESIZE);
+        page.mutate()[..2].copy_from_slice(&(PAGESIZE as u16).to_be_bytes());
+        assert_eq!(
+            page.get_string(0).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+
+        page.mutate()[..4].copy_from_slice(&[0, 2, 0xC3, 0x28]);
+        assert_eq!(
+            page.get_string(0).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn page_resize_up() {
+        let page = Page::from_vec(vec![5; PAGESIZE], PAGESIZE).unwrap();
+        let larger = page.resize_to(PAGESIZE * 2);
+        assert_eq!(larger.read().len(), PAGESIZE * 2);
+        assert!(larger.read()[..PAGESIZE].iter().all(|&byte| byte == 5));
+        assert!(larger.read()[PAGESIZE..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn page_resize_down() {
+        let page = Page::from_vec((0..PAGESIZE as u8).collect(), PAGESIZE).unwrap();
+        let smaller = page.resize_to(PAGESIZE / 2);
+        assert_eq!(smaller.read(), &page.read()[..PAGESIZE / 2]);
+    }
+
+    #[test]
+    fn page_xor_into() {
+        let old = Page::from_vec(vec![7; PAGESIZE], PAGESIZE).unwrap();
+        let mut new = Page::from_vec(vec![7; PAGESIZE], PAGESIZE).unwrap();
+        new.mutate()[4..8].copy_from_slice(&[1, 2, 3, 4]);
+
+        let mut delta = Page::new(PAGESIZE);
+        old.xor_into(&new, &mut delta);
+
+        assert!(delta.read()[..4].iter().all(|&byte| byte == 0));
+        assert_eq!(&delta.read()[4..8], &[7 ^ 1, 7 ^ 2, 7 ^ 3, 7 ^ 4]);
+        assert!(delta.read()[8..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_xor_into_wrong_size() {
+        let mut out = Page::new(PAGESIZE);
+        Page::new(PAGESIZE).xor_into(&Page::new(PAGESIZE * 2), &mut out);
+    }
+
+    #[test]
+    fn page_apply_delta() {
+        let old = Page::from_vec((0..PAGESIZE as u8).collect(), PAGESIZE).unwrap();
+        let mut new = old.clone();
+        new.mutate()[10..14].fill(0xAA);
+
+        let mut delta = Page::new(PAGESIZE);
+        old.xor_into(&new, &mut delta);
+
+        let mut restored = old.clone();
+        restored.apply_delta(&delta);
+        assert_eq!(restored.read(), new.read());
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_apply_delta_wrong_size() {
+        Page::new(PAGESIZE).apply_delta(&Page::new(PAGESIZE / 2));
+    }
+
+    #[test]
+    fn page_rotate() {
+        let mut page = Page::from_vec((0..PAGESIZE as u8).collect(), PAGESIZE).unwrap();
+        page.rotate(3);
+        assert_eq!(page.read()[..3], [3, 4, 5]);
+        assert_eq!(page.read()[PAGESIZE - 3..], [0, 1, 2]);
+
+        page.rotate(PAGESIZE * 2 + (PAGESIZE - 3));
+        assert_eq!(page.read(), (0..PAGESIZE as u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn page_changed_ranges() {
+        let old = Page::new(PAGESIZE);
+        assert!(old.changed_ranges(&old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.mutate()[2..5].fill(1);
+        new.mutate()[20..24].fill(2);
+        assert_eq!(old.changed_ranges(&new), vec![2..5, 20..24]);
+
+        new.mutate()[PAGESIZE - 1] = 3;
+        assert_eq!(
+            old.changed_ranges(&new),
+            vec![2..5, 20..24, PAGESIZE - 1..PAGESIZE]
+        );
+    }
+
+    #[test]
+    fn page_ct_eq() {
+        let page = Page::from_vec((0..PAGESIZE as u8).collect(), PAGESIZE).unwrap();
+        assert!(page.ct_eq(&page.clone()));
+
+        for position in [0, PAGESIZE / 2, PAGESIZE - 1] {
+            let mut other = page.clone();
+            other.mutate()[position] ^= 0x80;
+            assert!(!page.ct_eq(&other));
+        }
+        assert!(!page.ct_eq(&Page::new(PAGESIZE * 2)));
+    }
+
+    #[test]
+    fn page_magic() {
+        let mut page = Page::new(PAGESIZE);
+        page.write_magic(4, b"EDB1");
+
+        assert!(page.check_magic(4, b"EDB1"));
+        assert!(!page.check_magic(4, b"EDB2"));
+        assert!(!page.check_magic(0, b"EDB1"));
+        assert!(!page.check_magic(PAGESIZE - 2, b"EDB1"));
+        assert!(!page.check_magic(usize::MAX, b"EDB1"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried accessing 4 bytes")]
+    fn page_magic_out_of_bounds() {
+        Page::new(PAGESIZE).write_magic(PAGESIZE - 2, b"EDB1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried accessing 4 bytes")]
+    fn page_magic_offset_overflow() {
+        Page::new(PAGESIZE).write_magic(usize::MAX, b"EDB1");
+    }
+
+    #[test]
+    fn page_write_fields() {
+        let mut page = Page::new(PAGESIZE);
+        page.write_fields(&[
+            (0, FieldValue::U16(0x0102)),
+            (2, FieldValue::U32(0x03040506)),
+            (6, FieldValue::I32(-1)),
+            (10, FieldValue::Bytes(b"ab".to_vec())),
+            (PAGESIZE - 8, FieldValue::U64(7)),
+        ])
+        .unwrap();
+        assert_eq!(
+            &page.read()[..12],
+            &[1, 2, 3, 4, 5, 6, 255, 255, 255, 255, 97, 98]
+        );
+        assert_eq!(&page.read()[PAGESIZE - 8..], &[0, 0, 0, 0, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn page_write_fields_all_or_nothing() {
+        let mut page = Page::new(PAGESIZE);
+        let err = page
+            .write_fields(&[
+                (0, FieldValue::U32(0xffffffff)),
+                (PAGESIZE - 1, FieldValue::U16(1)),
+                (4, FieldValue::U8(1)),
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PageError::OutOfBounds {
+                offset,
+                len: 2,
+                page_size: PAGESIZE
+            } if offset == PAGESIZE - 1
+        ));
+        assert!(page.read().iter().all(|&byte| byte == 0));
+
+        let err = page
+            .write_fields(&[(usize::MAX, FieldValue::U8(1))])
+            .unwrap_err();
+        assert!(matches!(err, PageError::OutOfBounds { .. }));
+    }
+
+    #[test]
+    fn page_as_array() {
+        let mut page = Page::from_vec((0..PAGESIZE as u8).collect(), PAGESIZE).unwrap();
+        let words = page.as_array::<4>();
+        assert_eq!(words.len(), PAGESIZE / 4);
+        assert_eq!(words[1], [4, 5, 6, 7]);
+
+        page.as_array_mut::<8>()[1] = [0xff; 8];
+        assert_eq!(page.as_array::<8>()[0], [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(&page.read()[8..16], &[0xff; 8]);
+
+        // The 2 bytes left over don't make an element
+        let mut page = Page::new(10);
+        assert_eq!(page.as_array::<4>().len(), 2);
+        page.as_array_mut::<4>()[1] = [1; 4];
+        assert_eq!(page.read(), &[0, 0, 0, 0, 1, 1, 1, 1, 0, 0]);
+    }
+
+    // Everything on disk is big-endian whatever the host, so these bytes are the same on every
+    // machine. 0xcbf43926 is the standard CRC-32 check value for "123456789"
+    #[test]
+    fn on_disk_byte_order() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), 13)
+            .unwrap()
+            .with_checksums()
+            .unwrap();
+        let mut page = Page::new(13);
+        page.mutate()[4..].copy_from_slice(b"123456789");
+        manager.append_page(&page).unwrap();
+
+        let mut page = Page::new(13);
+        page.set_i32(4, 0x01020304);
+        page.write_fields(&[(8, FieldValue::U16(0x0506)), (10, FieldValue::U8(7))])
+            .unwrap();
+        manager.append_page(&page).unwrap();
+        manager.sync().unwrap();
+
+        let raw = fs::read(&file_path).unwrap();
+        assert_eq!(&raw[..4], &[0xcb, 0xf4, 0x39, 0x26]);
+        assert_eq!(&raw[13 + 4..13 + 11], &[1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(&raw[13..13 + 4], &crc32(&raw[13 + 4..26]).to_be_bytes(),);
+        assert!(manager.read_page(1).unwrap().verify_checksum());
+    }
+
+    #[test]
+    fn page_byte_entropy() {
+        let constant = Page::from_vec(vec![7; 4096], 4096).unwrap();
+        assert!(constant.byte_entropy().abs() < 1e-9);
+
+        let mut state: u32 = 0x9e3779b9;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let random = Page::from_vec(random, 4096).unwrap();
+        assert!(random.byte_entropy() > 7.9);
+
+        let halves = Page::from_vec([vec![0; 2048], vec![1; 2048]].concat(), 4096).unwrap();
+        assert!((halves.byte_entropy() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn page_rolling_hashes() {
+        let mut first = Page::new(PAGESIZE);
+        let mut second = Page::new(PAGESIZE);
+        for (i, byte) in first.mutate().iter_mut().enumerate() {
+            *byte = i as u8 * 7;
+        }
+        second.mutate().fill(0xff);
+        first.mutate()[3..11].copy_from_slice(b"shared!!");
+        second.mutate()[17..25].copy_from_slice(b"shared!!");
+
+        let window = 8;
+        let first_hashes = first.rolling_hashes(window);
+        let second_hashes = second.rolling_hashes(window);
+        assert_eq!(first_hashes.len(), PAGESIZE - window + 1);
+        assert_eq!(first_hashes[3], second_hashes[17]);
+        assert_ne!(first_hashes[2], second_hashes[17]);
+        assert_ne!(first_hashes[4], second_hashes[17]);
+
+        // Rolling gives the same hash as hashing each window from scratch
+        for (i, &hash) in first_hashes.iter().enumerate() {
+            let alone = Page::from_vec(first.read()[i..i + window].to_vec(), window).unwrap();
+            assert_eq!(alone.rolling_hashes(window), vec![hash]);
+        }
+
+        assert_eq!(first.rolling_hashes(PAGESIZE).len(), 1);
+        assert!(first.rolling_hashes(PAGESIZE + 1).is_empty());
+    }
+
+    #[test]
+    fn page_find_bytes() {
+        let mut page = Page::new(PAGESIZE);
+        page.mutate()[3..6].copy_from_slice(b"key");
+        assert_eq!(page.find_bytes(b"key"), Some(3));
+        assert_eq!(page.rfind_bytes(b"key"), Some(3));
+
+        page.mutate()[20..23].copy_from_slice(b"key");
+        assert_eq!(page.find_bytes(b"key"), Some(3));
+        assert_eq!(page.rfind_bytes(b"key"), Some(20));
+
+        assert_eq!(page.find_bytes(b"nope"), None);
+        assert_eq!(page.rfind_bytes(b"nope"), None);
+        assert_eq!(page.find_bytes(&[0; PAGESIZE + 1]), None);
+    }
+
+    #[test]
+    fn page_bits() {
+        let mut page = Page::new(PAGESIZE);
+        page.set_bit(0);
+        page.set_bit(9);
+        page.set_bit(PAGESIZE * 8 - 1);
+        assert!(page.get_bit(0));
+        assert!(!page.get_bit(1));
+        assert!(page.get_bit(9));
+        assert!(page.get_bit(PAGESIZE * 8 - 1));
+        assert_eq!(page.read()[1], 0b10);
+
+        page.clear_bit(9);
+        assert!(!page.get_bit(9));
+        assert_eq!(page.read()[1], 0);
+    }
+
+    #[test]
+    fn page_first_clear_bit() {
+        let mut page = Page::new(PAGESIZE);
+        assert_eq!(page.first_clear_bit(), Some(0));
+
+        for bit in 0..11 {
+            page.set_bit(bit);
+        }
+        page.set_bit(12);
+        assert_eq!(page.first_clear_bit(), Some(11));
+
+        page.clear_bit(3);
+        assert_eq!(page.first_clear_bit(), Some(3));
+
+        page.mutate().fill(u8::MAX);
+        assert_eq!(page.first_clear_bit(), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_bit_out_of_bounds() {
+        Page::new(PAGESIZE).get_bit(PAGESIZE * 8);
+    }
+
+    #[test]
+    fn cow_page_shares_until_mutated() {
+        let page = Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap();
+
+        let reader = CowPage::new(&page);
+        assert!(!reader.is_copied());
+        assert!(std::ptr::eq(reader.read(), page.read()));
+
+        let mut writer = CowPage::new(&page);
+        writer.make_mut()[0] = 9;
+        assert!(writer.is_copied());
+        assert_eq!(writer.read()[0], 9);
+        assert!(page.read().iter().all(|&byte| byte == 1));
+
+        let version = writer.into_page();
+        assert_eq!(version.read()[..2], [9, 1]);
+    }
+
+    #[test]
+    fn page_manager_read_write() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let page = Page::from_vec(vec![3; PAGESIZE], PAGESIZE).unwrap();
+        manager.write_page(0, &page).unwrap();
+
+        let page = manager.read_page(0).unwrap();
+        assert!(page.read().iter().all(|&byte| byte == 3));
+    }
+
+    #[test]
+    fn page_manager_write_wrong_size() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let err = manager.write_page(0, &Page::new(PAGESIZE * 2)).unwrap_err();
+        assert!(matches!(
+            err,
+            PageError::SizeMismatch {
+                expected: PAGESIZE,
+                got
+            } if got == PAGESIZE * 2
+        ));
+        let err = manager.append_page(&Page::new(PAGESIZE - 1)).unwrap_err();
+        assert!(matches!(err, PageError::SizeMismatch { .. }));
+        assert_eq!(manager.n_pages().unwrap(), 0);
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn page_checksum() {
+        let mut data = vec![0; 4];
+        data.extend_from_slice(b"123456789");
+        let mut page = Page::from_vec(data, 13).unwrap();
+        assert_eq!(page.compute_checksum(), 0xcbf43926);
+        assert!(!page.verify_checksum());
+
+        page.write_checksum();
+        assert_eq!(&page.read()[..4], &[0xcb, 0xf4, 0x39, 0x26]);
+        assert!(page.verify_checksum());
+
+        page.mutate()[7] ^= 0x10;
+        assert!(!page.verify_checksum());
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_checksum_too_small() {
+        Page::new(3).compute_checksum();
+    }
+
+    #[test]
+    fn page_manager_checksums() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .unwrap()
+            .with_checksums()
+            .unwrap();
+
+        let page = Page::from_vec(vec![7; PAGESIZE], PAGESIZE).unwrap();
+        manager.append_page(&page).unwrap();
+        manager.write_page(1, &page).unwrap();
+        let read = manager.read_page(1).unwrap();
+        assert!(read.verify_checksum());
+        assert_eq!(&read.read()[4..], &page.read()[4..]);
+
+        // Flip a bit behind the manager's back
+        let mut file = OpenOptions::new().write(true).open(&file_path).unwrap();
+        file.seek(SeekFrom::Start(PAGESIZE as u64 + 10)).unwrap();
+        file.write_all(&[6]).unwrap();
+
+        assert!(manager.read_page(0).is_ok());
+        let err = manager.read_page(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn page_manager_checksums_page_too_small() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let manager = PageManager::new(file_path.to_str().unwrap(), 3).unwrap();
+        let err = manager.with_checksums().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(PageManager::new(file_path.to_str().unwrap(), 4)
+            .unwrap()
+            .with_checksums()
+            .is_ok());
+    }
+
+    #[test]
+    fn page_manager_read_page_into() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        for i in 0..4 {
+            manager
+                .append_page(&Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+
+        let mut page = Page::new(PAGESIZE);
+        let ptr = page.read().as_ptr();
+        for i in [2, 0, 3, 1] {
+            manager.read_page_into(i, &mut page).unwrap();
+            assert_eq!(page.read(), &[i as u8; PAGESIZE]);
+            assert_eq!(page.read().as_ptr(), ptr);
+        }
+
+        let err = manager.read_page_into(4, &mut page).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    #[should_panic]
+    fn page_manager_read_page_into_wrong_size() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
+        let _ = manager.read_page_into(0, &mut Page::new(PAGESIZE - 1));
+    }
+
+    #[test]
+    fn page_manager_sync() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .unwrap()
+            .with_buffered_writes();
+        manager
+            .append_page(&Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+        manager.sync().unwrap();
+        manager
+            .append_page(&Page::from_vec(vec![2; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+        manager.sync_data().unwrap();
+
+        // Nothing left in the buffer for drop to write, so the pages read back are the synced ones
+        assert!(manager.write_buffer.as_ref().unwrap().is_empty());
+        drop(manager);
+        let mut reopened =
+            PageManager::open_read_only(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        assert_eq!(reopened.n_pages().unwrap(), 2);
+        assert_eq!(reopened.read_page(0).unwrap().read(), &[1; PAGESIZE]);
+        assert_eq!(reopened.read_page(1).unwrap().read(), &[2; PAGESIZE]);
+    }
+
+    #[test]
+    fn page_manager_range_reader() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        for i in 0..8 {
+            manager
+                .append_page(&Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+
+        let mut reader = manager.range_reader(2, 3).unwrap();
+        // Moving the manager's cursor doesn't disturb the reader
+        manager.read_page(7).unwrap();
+        let mut streamed = Vec::new();
+        reader.read_to_end(&mut streamed).unwrap();
+
+        let expected: Vec<u8> = (2..5)
+            .flat_map(|i| manager.read_page(i).unwrap().read().to_vec())
+            .collect();
+        assert_eq!(streamed, expected);
+
+        // A range running off the end stops at the end of the file
+        let mut streamed = Vec::new();
+        let mut reader = manager.range_reader(6, 5).unwrap();
+        reader.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed.len(), 2 * PAGESIZE);
+    }
+
+    // Fails every read while the switch is on, like a flaky network disk
+    struct FlakyStorage {
+        inner: MemStorage,
+        failing: Rc<Cell<bool>>,
+    }
+
+    impl Read for FlakyStorage {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failing.get() {
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FlakyStorage {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FlakyStorage {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Storage for FlakyStorage {
+        fn size(&self) -> Result<u64, io::Error> {
+            self.inner.size()
+        }
+
+        fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+            self.inner.set_len(len)
+        }
+
+        fn sync_all(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn page_manager_stale_reads() {
+        let failing = Rc::new(Cell::new(false));
+        let storage = FlakyStorage {
+            inner: MemStorage::new(),
+            failing: Rc::clone(&failing),
+        };
+        let mut manager = PageManager::with_storage(Box::new(storage), PAGESIZE);
+        for i in 0..2 {
+            manager
+                .append_page(&Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+        manager.set_read_cache(4);
+
+        let read = manager.read_page_or_stale(0).unwrap();
+        assert!(!read.stale);
+        assert_eq!(read.page.read(), &[0; PAGESIZE]);
+
+        failing.set(true);
+        let read = manager.read_page_or_stale(0).unwrap();
+        assert!(read.stale);
+        assert_eq!(read.page.read(), &[0; PAGESIZE]);
+
+        // Nothing cached to fall back on
+        let err = manager.read_page_or_stale(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        failing.set(false);
+        assert!(!manager.read_page_or_stale(1).unwrap().stale);
+    }
+
+    // A handle whose file went away underneath it, every operation fails
+    #[cfg(target_os = "linux")]
+    struct StaleStorage;
+
+    #[cfg(target_os = "linux")]
+    fn stale() -> io::Error {
+        io::Error::from_raw_os_error(libc::ESTALE)
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Read for StaleStorage {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(stale())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Write for StaleStorage {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(stale())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(stale())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Seek for StaleStorage {
+        fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+            Err(stale())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    impl Storage for StaleStorage {
+        fn size(&self) -> Result<u64, io::Error> {
+            Err(stale())
+        }
+
+        fn set_len(&mut self, _len: u64) -> Result<(), io::Error> {
+            Err(stale())
+        }
+
+        fn sync_all(&mut self) -> Result<(), io::Error> {
+            Err(stale())
+        }
+
+        fn sync_data(&mut self) -> Result<(), io::Error> {
+            Err(stale())
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn page_manager_reopen_on_stale() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .unwrap()
+            .with_reopen_on_stale();
+        manager
+            .append_page(&Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
 
-    pub fn mutate(&mut self) -> &mut Vec<u8> {
-        &mut self.data
-    }
-}
+        manager.storage = Box::new(StaleStorage);
+        assert_eq!(manager.read_page(0).unwrap().read(), &[1; PAGESIZE]);
 
-pub struct PageManager {
-    pub file: File,
-    pub page_size: usize,
-}
+        manager.storage = Box::new(StaleStorage);
+        let page = Page::from_vec(vec![2; PAGESIZE], PAGESIZE).unwrap();
+        manager.write_page(0, &page).unwrap();
 
-impl PageManager {
-    pub fn new(path: &str, page_size: usize) -> Result<Self, io::Error> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(false)
-            .create(true)
-            .open(path)?;
-        Ok(Self { file, page_size })
+        manager.storage = Box::new(StaleStorage);
+        let page = Page::from_vec(vec![3; PAGESIZE], PAGESIZE).unwrap();
+        assert_eq!(manager.append_page(&page).unwrap(), 1);
+
+        manager.storage = Box::new(StaleStorage);
+        manager.write_bytes(1, 0, &[4; 4]).unwrap();
+
+        assert_eq!(manager.read_page(0).unwrap().read(), &[2; PAGESIZE]);
+        let mut expected = [3; PAGESIZE];
+        expected[..4].copy_from_slice(&[4; 4]);
+        assert_eq!(manager.read_page(1).unwrap().read(), &expected[..]);
     }
-}
 
-impl PageManager {
-    pub fn read_page(&mut self, position: usize) -> Result<Page, io::Error> {
-        let mut buf = vec![0; self.page_size];
-        let offset = (position * self.page_size)
-            .try_into()
-            .expect("usize couldn't be converted into u64");
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn page_manager_reopen_on_stale_when_locked() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .unwrap()
+            .with_reopen_on_stale();
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
 
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.read_exact(&mut buf)?;
+        // Losing the handle lets another manager take the lock before the reopen
+        manager.storage = Box::new(StaleStorage);
+        let mut other = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
 
-        Ok(Page::from_vec(buf, self.page_size))
+        let err = manager.read_page(0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let page = Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap();
+        assert!(manager.write_page(0, &page).is_err());
+        assert!(manager.append_page(&page).is_err());
+        drop(manager);
+        assert_eq!(other.n_pages().unwrap(), 1);
+        assert_eq!(other.read_page(0).unwrap().read(), &[0; PAGESIZE]);
     }
 
-    pub fn write_page(&mut self, position: usize, page: &Page) -> Result<(), io::Error> {
-        if page.read().len() != self.page_size {
-            panic!(
-                "Tried write page with size {} when page size is set to {}",
-                page.read().len(),
-                self.page_size
-            );
-        }
-        let offset = (position * self.page_size)
-            .try_into()
-            .expect("usize couldn't be converted into u64");
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(page.read())
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn page_manager_stale_without_reopen() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
+
+        manager.storage = Box::new(StaleStorage);
+        let err = manager.read_page(0).unwrap_err();
+        assert!(is_stale(&err));
+
+        // Nothing to reopen without a path
+        let mut manager =
+            PageManager::with_storage(Box::new(StaleStorage), PAGESIZE).with_reopen_on_stale();
+        let err = manager.read_page(0).unwrap_err();
+        assert!(is_stale(&err));
     }
 
-    pub fn append_page(&mut self, page: &Page) -> Result<usize, io::Error> {
-        if page.read().len() != self.page_size {
-            panic!(
-                "Tried appending page with size {} when page size is set to {}",
-                page.read().len(),
-                self.page_size
-            );
+    #[test]
+    fn page_manager_parity_repair() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager =
+            PageManager::with_parity(file_path.to_str().unwrap(), PAGESIZE, 3).unwrap();
+        let pages: Vec<Page> = (1..=5)
+            .map(|i| Page::from_vec(vec![i * 17; PAGESIZE], PAGESIZE).unwrap())
+            .collect();
+        for page in &pages {
+            manager.append_page(page).unwrap();
         }
-        let filesize = self.file.metadata()?.len() as usize;
-        let new_page_position = filesize / self.page_size;
+        // Overwrites and partial writes keep the parity up to date too
+        manager.write_page(1, &pages[4]).unwrap();
+        manager.write_bytes(4, 3, b"abc").unwrap();
+        let expected = [manager.read_page(1).unwrap(), manager.read_page(4).unwrap()];
+        assert!((0..6).all(|position| manager.verify_parity(position).unwrap()));
 
-        self.file.seek(SeekFrom::End(0))?;
-        self.file.write_all(page.read())?;
+        for (position, expected) in [1, 4].into_iter().zip(expected) {
+            manager
+                .storage
+                .seek(SeekFrom::Start((position * PAGESIZE + 2) as u64))
+                .unwrap();
+            manager.storage.write_all(&[0xee; 5]).unwrap();
+            assert!(!manager.verify_parity(position).unwrap());
 
-        Ok(new_page_position)
+            let repaired = manager.repair_page(position).unwrap();
+            assert_eq!(repaired.read(), expected.read());
+            assert_eq!(manager.read_page(position).unwrap().read(), expected.read());
+            assert!(manager.verify_parity(position).unwrap());
+        }
+
+        // Repairing didn't disturb the parity for the rest of the group
+        assert!(manager.verify_parity(0).unwrap());
+        assert!(manager.verify_parity(2).unwrap());
     }
 
-    pub fn n_pages(&self) -> Result<usize, io::Error> {
-        let filesize = self.file.metadata()?.len();
+    #[test]
+    fn page_manager_defragment_with_parity() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager =
+            PageManager::with_parity(file_path.to_str().unwrap(), PAGESIZE, 3).unwrap();
+        for i in 1..=8 {
+            manager
+                .append_page(&Page::from_vec(vec![i * 17; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
 
-        assert!(filesize as usize % self.page_size == 0);
-        Ok(filesize as usize / self.page_size)
-    }
-}
+        // Cuts pages out of both the second and the third group
+        manager.defragment(&[0, 2, 4, 7]).unwrap();
+        assert_eq!(manager.n_pages().unwrap(), 4);
+        assert!((0..6).all(|position| manager.verify_parity(position).unwrap()));
 
-#[cfg(test)]
-mod test {
-    const PAGESIZE: usize = 32;
-    use super::*;
-    use tempfile::tempdir;
+        let expected = manager.read_page(3).unwrap();
+        manager
+            .storage
+            .seek(SeekFrom::Start((3 * PAGESIZE) as u64))
+            .unwrap();
+        manager.storage.write_all(&[0xee; 4]).unwrap();
+        assert_eq!(manager.repair_page(3).unwrap().read(), expected.read());
+        assert_eq!(expected.read(), &[8 * 17; PAGESIZE]);
+    }
 
     #[test]
-    fn page_init() {
-        let page = Page::new(PAGESIZE);
-        assert!(page.data.iter().all(|&byte| byte == 0));
+    fn page_manager_repair_without_parity() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
+        let err = io::Error::from(manager.repair_page(0).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
     }
 
     #[test]
-    fn page_from_vec() {
-        let page = Page::from_vec(vec![1; PAGESIZE], PAGESIZE);
-        assert!(page.data.iter().all(|&byte| byte == 1));
+    fn page_manager_read_pages_scatter() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .unwrap()
+            .with_checksums()
+            .unwrap();
+        for i in 0..10 {
+            manager
+                .append_page(&Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+
+        let positions = [0, 1, 2, 5, 7, 8, 3];
+        let mut out = vec![Page::new(PAGESIZE); positions.len()];
+        let disk_reads = manager.disk_reads;
+        manager.read_pages_scatter(&positions, &mut out).unwrap();
+        assert_eq!(manager.disk_reads - disk_reads, 4);
+        for (&position, page) in positions.iter().zip(&out) {
+            assert_eq!(page.read(), manager.read_page(position).unwrap().read());
+        }
+
+        // A cached page in a run is copied, the rest is read one by one
+        manager.set_read_cache(2);
+        manager.read_page(6).unwrap();
+        let disk_reads = manager.disk_reads;
+        let mut out = vec![Page::new(PAGESIZE); 3];
+        manager.read_pages_scatter(&[5, 6, 7], &mut out).unwrap();
+        assert_eq!(manager.disk_reads - disk_reads, 2);
+        assert_eq!(out[1].read(), manager.read_page(6).unwrap().read());
     }
 
     #[test]
-    #[should_panic]
-    fn page_from_wrong_vec() {
-        let _page = Page::from_vec(vec![1; PAGESIZE * 2], PAGESIZE);
+    fn page_manager_read_pages_scatter_errors() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        for _ in 0..3 {
+            manager.append_page(&Page::new(PAGESIZE)).unwrap();
+        }
+
+        let mut out = vec![Page::new(PAGESIZE); 2];
+        let err = manager.read_pages_scatter(&[0], &mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let mut out = vec![Page::new(PAGESIZE), Page::new(PAGESIZE + 1)];
+        let err = manager.read_pages_scatter(&[0, 1], &mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        let mut out = vec![Page::new(PAGESIZE); 2];
+        let err = manager.read_pages_scatter(&[2, 3], &mut out).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("Page 3"));
+
+        manager.storage.set_len(PAGESIZE as u64 * 2 + 5).unwrap();
+        let err = manager.read_pages_scatter(&[1, 2], &mut out).unwrap_err();
+        let short = err.get_ref().unwrap().downcast_ref::<ShortRead>().unwrap();
+        assert_eq!((short.position, short.readable), (2, 5));
     }
 
     #[test]
-    fn page_read() {
-        let page = Page::from_vec(vec![2; PAGESIZE], PAGESIZE);
-        assert!(page.read().iter().all(|&byte| byte == 2));
+    fn page_manager_write_bytes() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.set_read_cache(2);
+        for i in 0..2 {
+            manager
+                .append_page(&Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+        manager.read_page(1).unwrap();
+
+        manager.write_bytes(1, 4, b"abc").unwrap();
+        let page = manager.read_page(1).unwrap();
+        assert_eq!(&page.read()[3..8], &[1, 97, 98, 99, 1]);
+        assert_eq!(manager.read_page(0).unwrap().read(), &[0; PAGESIZE]);
+
+        let err = manager.write_bytes(1, PAGESIZE - 2, b"abc").unwrap_err();
+        assert!(matches!(err, PageError::OutOfBounds { len: 3, .. }));
+
+        // The hook still sees the whole page
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let hook_written = Rc::clone(&written);
+        manager.set_write_hook(move |position, page| {
+            hook_written
+                .borrow_mut()
+                .push((position, page.read().to_vec()))
+        });
+        manager.write_bytes(0, 0, b"z").unwrap();
+        let mut expected = vec![0; PAGESIZE];
+        expected[0] = b'z';
+        assert_eq!(*written.borrow(), vec![(0, expected)]);
     }
 
     #[test]
-    fn page_mutate() {
-        let mut mutable_page = Page::new(PAGESIZE);
-        mutable_page.mutate().fill(2);
-        assert!(mutable_page.read().iter().all(|&byte| byte == 2));
+    fn page_manager_bulk_append() {
+        let dir = tempdir().unwrap();
+        let page = |i: usize| Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+
+        let file_path = dir.path().join("single.bin");
+        let mut single = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        for i in 0..1000 {
+            assert_eq!(single.append_page(&page(i)).unwrap(), i);
+        }
+        // One seek to find the end, then nothing but writes
+        assert_eq!(single.append_syscalls, 1001);
+
+        let file_path = dir.path().join("bulk.bin");
+        let mut bulk = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        bulk.begin_bulk();
+        for i in 0..1000 {
+            assert_eq!(bulk.append_page(&page(i)).unwrap(), i);
+        }
+        assert_eq!(bulk.n_pages().unwrap(), 1000);
+        bulk.end_bulk().unwrap();
+        assert_eq!(bulk.append_syscalls, 2);
+
+        assert_eq!(bulk.file_digest().unwrap(), single.file_digest().unwrap());
+        for i in [0, 1, 500, 999] {
+            assert_eq!(bulk.read_page(i).unwrap().read(), page(i).read());
+        }
     }
 
     #[test]
-    fn page_manager_read_write() {
+    fn page_manager_bulk_append_interleaved() {
         let dir = tempdir().unwrap();
         let file_path = dir.path().join("testfile.bin");
         let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let hook_written = Rc::clone(&written);
+        manager.set_write_hook(move |position, _| hook_written.borrow_mut().push(position));
 
-        let page = Page::from_vec(vec![3; PAGESIZE], PAGESIZE);
-        manager.write_page(0, &page).unwrap();
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
+        manager.begin_bulk();
+        manager
+            .append_page(&Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+        manager
+            .append_page(&Page::from_vec(vec![2; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
 
-        let page = manager.read_page(0).unwrap();
-        assert!(page.read().iter().all(|&byte| byte == 3));
+        // Touching a collected page writes the collected pages out first
+        assert_eq!(manager.read_page(2).unwrap().read(), &[2; PAGESIZE]);
+        manager
+            .write_page(1, &Page::from_vec(vec![3; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+        assert_eq!(manager.append_page(&Page::new(PAGESIZE)).unwrap(), 3);
+        manager.end_bulk().unwrap();
+
+        assert_eq!(manager.n_pages().unwrap(), 4);
+        assert_eq!(manager.read_page(1).unwrap().read(), &[3; PAGESIZE]);
+        assert_eq!(*written.borrow(), vec![0, 1, 2, 1, 3]);
+
+        // Appends after a write elsewhere in the file still land at the end
+        manager
+            .write_page(0, &Page::from_vec(vec![4; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+        assert_eq!(manager.append_page(&Page::new(PAGESIZE)).unwrap(), 4);
+        assert_eq!(manager.read_page(0).unwrap().read(), &[4; PAGESIZE]);
     }
 
     #[test]
@@ -161,7 +2784,7 @@ mod test {
         let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
 
         for i in 0..=3 {
-            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE);
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
             manager.append_page(&page).unwrap();
         }
 
@@ -178,7 +2801,7 @@ mod test {
         let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
 
         for i in 0..=10 {
-            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE);
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
             manager.write_page(i, &page).unwrap();
         }
 
@@ -197,10 +2820,369 @@ mod test {
         assert!(manager.read_page(0).is_err());
 
         for i in 0..3 {
-            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE);
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
             manager.append_page(&page).unwrap();
         }
 
         assert!(manager.read_page(3).is_err());
     }
+
+    #[test]
+    fn page_manager_read_torn_page() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let page = Page::from_vec(vec![1; PAGESIZE], PAGESIZE).unwrap();
+        manager.append_page(&page).unwrap();
+        manager.storage.seek(SeekFrom::End(0)).unwrap();
+        manager.storage.write_all(&[2; 10]).unwrap();
+
+        let err = manager.read_page(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let short = err.get_ref().unwrap().downcast_ref::<ShortRead>().unwrap();
+        assert_eq!(short.position, 1);
+        assert_eq!(short.readable, 10);
+        assert_eq!(short.page_size, PAGESIZE);
+
+        // A page that isn't there at all is not a torn page
+        let err = manager.read_page(2).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(err.get_ref().unwrap().downcast_ref::<ShortRead>().is_none());
+    }
+
+    #[test]
+    fn page_manager_iter_with_positions() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        assert_eq!(manager.iter_with_positions().count(), 0);
+
+        for i in 0..5 {
+            let mut page = Page::new(PAGESIZE);
+            page.mutate()[0] = 10 + i as u8;
+            manager.append_page(&page).unwrap();
+        }
+
+        let pairs: Vec<_> = manager
+            .iter_with_positions()
+            .map(|result| {
+                let (position, page) = result.unwrap();
+                (position, page.read()[0])
+            })
+            .collect();
+        assert_eq!(pairs, vec![(0, 10), (1, 11), (2, 12), (3, 13), (4, 14)]);
+    }
+
+    #[test]
+    fn page_manager_prefetch_range() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        for i in 0..8 {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+
+        manager.prefetch_range(2, 4).unwrap();
+        // Running past the end of the file is only a hint and shouldn't fail
+        manager.prefetch_range(6, 10).unwrap();
+
+        for i in 0..8 {
+            let page = manager.read_page(i).unwrap();
+            assert!(page.read().iter().all(|&byte| byte == (i as u8)));
+        }
+    }
+
+    #[test]
+    fn page_manager_defragment() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        for i in 0..8 {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+
+        let remap = manager.defragment(&[6, 1, 3, 1]).unwrap();
+        assert_eq!(remap, vec![(1, 0), (3, 1), (6, 2)]);
+        assert_eq!(manager.n_pages().unwrap(), 3);
+
+        for (old, new) in remap {
+            let page = manager.read_page(new).unwrap();
+            assert!(page.read().iter().all(|&byte| byte == (old as u8)));
+        }
+    }
+
+    #[test]
+    fn page_manager_read_cache() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.set_read_cache(2);
+
+        for i in 0..3 {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+
+        manager.read_page(0).unwrap();
+        let page = manager.read_page(0).unwrap();
+        assert!(page.read().iter().all(|&byte| byte == 0));
+        assert_eq!(manager.disk_reads, 1);
+
+        // Writing a cached position must not leave the old contents behind
+        let page = Page::from_vec(vec![7; PAGESIZE], PAGESIZE).unwrap();
+        manager.write_page(0, &page).unwrap();
+        let page = manager.read_page(0).unwrap();
+        assert!(page.read().iter().all(|&byte| byte == 7));
+        assert_eq!(manager.disk_reads, 2);
+
+        // 0 was used more recently than 1, so reading 2 evicts 1
+        manager.read_page(1).unwrap();
+        manager.read_page(0).unwrap();
+        manager.read_page(2).unwrap();
+        assert_eq!(manager.disk_reads, 4);
+        manager.read_page(0).unwrap();
+        assert_eq!(manager.disk_reads, 4);
+        manager.read_page(1).unwrap();
+        assert_eq!(manager.disk_reads, 5);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn page_manager_durable_publish() {
+        let dir = tempdir().unwrap();
+        let tmp_path = dir.path().join("testfile.bin.tmp");
+        let final_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(tmp_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let page = Page::from_vec(vec![5; PAGESIZE], PAGESIZE).unwrap();
+        manager.append_page(&page).unwrap();
+        manager
+            .durable_publish(final_path.to_str().unwrap())
+            .unwrap();
+
+        assert!(!tmp_path.exists());
+        assert_eq!(manager.path, Some(final_path.clone()));
+        drop(manager);
+
+        let mut published = PageManager::new(final_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let page = published.read_page(0).unwrap();
+        assert!(page.read().iter().all(|&byte| byte == 5));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn page_manager_locks_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let err = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(err.to_string().contains("locked"));
+        let err = PageManager::open_read_only(file_path.to_str().unwrap(), PAGESIZE)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+        drop(manager);
+        let manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        drop(manager);
+    }
+
+    #[test]
+    fn page_manager_read_only() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let page = Page::from_vec(vec![4; PAGESIZE], PAGESIZE).unwrap();
+        manager.append_page(&page).unwrap();
+        drop(manager);
+
+        let mut reader =
+            PageManager::open_read_only(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut other = PageManager::open_read_only(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        assert_eq!(reader.read_page(0).unwrap().read(), page.read());
+        assert_eq!(other.read_page(0).unwrap().read(), page.read());
+        #[cfg(target_os = "linux")]
+        {
+            let err = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        }
+
+        let denied = |result: Result<(), PageError>| {
+            assert!(matches!(
+                result,
+                Err(PageError::Io(err)) if err.kind() == ErrorKind::PermissionDenied
+            ));
+        };
+        denied(reader.write_page(0, &Page::new(PAGESIZE)));
+        denied(reader.write_bytes(0, 0, &[1]));
+        denied(reader.append_page(&Page::new(PAGESIZE)).map(|_| ()));
+        assert_eq!(
+            reader.defragment(&[0]).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(reader.n_pages().unwrap(), 1);
+        assert_eq!(reader.read_page(0).unwrap().read(), page.read());
+
+        drop(reader);
+        drop(other);
+        PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+    }
+
+    #[test]
+    fn page_manager_buffered_writes() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE)
+            .unwrap()
+            .with_buffered_writes();
+
+        for i in [0, 1, 2, 5] {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+            manager.write_page(i, &page).unwrap();
+        }
+        let page = Page::from_vec(vec![6; PAGESIZE], PAGESIZE).unwrap();
+        assert_eq!(manager.append_page(&page).unwrap(), 6);
+
+        assert_eq!(manager.storage.size().unwrap(), 0);
+        assert_eq!(manager.n_pages().unwrap(), 7);
+        for i in [0, 1, 2, 5, 6] {
+            let page = manager.read_page(i).unwrap();
+            assert!(page.read().iter().all(|&byte| byte == (i as u8)));
+        }
+
+        manager.flush().unwrap();
+        assert_eq!(manager.storage.size().unwrap(), (7 * PAGESIZE) as u64);
+        drop(manager);
+
+        let mut reopened = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        for i in [0, 1, 2, 5, 6] {
+            let page = reopened.read_page(i).unwrap();
+            assert!(page.read().iter().all(|&byte| byte == (i as u8)));
+        }
+        assert!(reopened
+            .read_page(3)
+            .unwrap()
+            .read()
+            .iter()
+            .all(|&b| b == 0));
+    }
+
+    #[test]
+    fn page_manager_file_digest() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let copy_path = dir.path().join("copy.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        for i in 0..5 {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+        fs::copy(&file_path, &copy_path).unwrap();
+        let mut copy = PageManager::new(copy_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let digest = manager.file_digest().unwrap();
+        assert_eq!(digest, copy.file_digest().unwrap());
+
+        let mut page = copy.read_page(3).unwrap();
+        page.mutate()[7] ^= 1;
+        copy.write_page(3, &page).unwrap();
+        assert_ne!(digest, copy.file_digest().unwrap());
+    }
+
+    #[test]
+    fn page_manager_read_page_shared() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager.set_read_cache(4);
+
+        let page = Page::from_vec(vec![8; PAGESIZE], PAGESIZE).unwrap();
+        manager.append_page(&page).unwrap();
+
+        let first = manager.read_page_shared(0).unwrap();
+        let second = manager.read_page_shared(0).unwrap();
+        let cloned = Arc::clone(&first);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&first, &cloned));
+        assert!(cloned.read().iter().all(|&byte| byte == 8));
+        assert_eq!(manager.disk_reads, 1);
+
+        // Writing replaces the cached copy, readers holding the old one keep it
+        let page = Page::from_vec(vec![9; PAGESIZE], PAGESIZE).unwrap();
+        manager.write_page(0, &page).unwrap();
+        let third = manager.read_page_shared(0).unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        assert!(first.read().iter().all(|&byte| byte == 8));
+        assert!(third.read().iter().all(|&byte| byte == 9));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn page_manager_sparse_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        const N_PAGES: usize = 4096;
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let copy_path = dir.path().join("copy.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        manager
+            .storage
+            .set_len((N_PAGES * PAGESIZE) as u64)
+            .unwrap();
+        for i in [3, 700, N_PAGES - 1] {
+            let page = Page::from_vec(vec![(i % 255) as u8 + 1; PAGESIZE], PAGESIZE).unwrap();
+            manager.write_page(i, &page).unwrap();
+        }
+
+        manager.sparse_copy(copy_path.to_str().unwrap()).unwrap();
+
+        assert_eq!(fs::read(&file_path).unwrap(), fs::read(&copy_path).unwrap());
+        let metadata = fs::metadata(&copy_path).unwrap();
+        assert_eq!(metadata.len(), (N_PAGES * PAGESIZE) as u64);
+        assert!(metadata.blocks() * 512 < metadata.len());
+    }
+
+    #[test]
+    fn page_manager_write_hook() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let hook_seen = Rc::clone(&seen);
+        manager.set_write_hook(move |position, page| {
+            hook_seen.borrow_mut().push((position, page.read()[0]));
+        });
+
+        for i in 1..=2 {
+            let page = Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+        manager
+            .write_page(0, &Page::from_vec(vec![3; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+        manager
+            .write_page(5, &Page::from_vec(vec![4; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+
+        assert_eq!(*seen.borrow(), vec![(0, 1), (1, 2), (0, 3), (5, 4)]);
+
+        manager.clear_write_hook();
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
+        assert_eq!(seen.borrow().len(), 4);
+    }
 }
@@ -0,0 +1,119 @@
+use std::cell::{Cell, RefCell};
+use std::ops::{Deref, DerefMut};
+
+use super::Page;
+
+const MAX_POOLED_BUFFERS: usize = 16;
+
+thread_local! {
+    static BUFFERS: RefCell<Vec<Box<[u8]>>> = const { RefCell::new(Vec::new()) };
+    static REUSES: Cell<usize> = const { Cell::new(0) };
+}
+
+// How many pooled reads on this thread got a recycled buffer instead of allocating
+pub fn pooled_buffer_reuses() -> usize {
+    REUSES.get()
+}
+
+// A page whose buffer goes back to the thread's pool when dropped
+pub struct PooledPage {
+    page: Page,
+}
+
+impl PooledPage {
+    pub(super) fn take(page_size: usize) -> Self {
+        let recycled = BUFFERS.with_borrow_mut(|buffers| {
+            let index = buffers.iter().position(|buf| buf.len() == page_size)?;
+            Some(buffers.swap_remove(index))
+        });
+        let data = match recycled {
+            Some(data) => {
+                REUSES.set(REUSES.get() + 1);
+                data
+            }
+            None => vec![0; page_size].into_boxed_slice(),
+        };
+        Self {
+            page: Page { data },
+        }
+    }
+
+    pub fn into_page(mut self) -> Page {
+        Page {
+            data: std::mem::take(&mut self.page.data),
+        }
+    }
+}
+
+impl Deref for PooledPage {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        &self.page
+    }
+}
+
+impl DerefMut for PooledPage {
+    fn deref_mut(&mut self) -> &mut Page {
+        &mut self.page
+    }
+}
+
+impl Drop for PooledPage {
+    fn drop(&mut self) {
+        let data = std::mem::take(&mut self.page.data);
+        if data.is_empty() {
+            return;
+        }
+        BUFFERS.with_borrow_mut(|buffers| {
+            if buffers.len() < MAX_POOLED_BUFFERS {
+                buffers.push(data);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PageManager;
+    use tempfile::tempdir;
+
+    const PAGESIZE: usize = 32;
+
+    #[test]
+    fn pooled_reads_recycle_buffers() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+
+        for i in 0..10 {
+            let page = Page::from_vec(vec![i as u8; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+
+        // Tests run on their own threads, so the pool starts out empty
+        let before = pooled_buffer_reuses();
+        for round in 0..3 {
+            for i in 0..10 {
+                let page = manager.read_page_pooled(i).unwrap();
+                assert!(page.read().iter().all(|&byte| byte == (i as u8)));
+            }
+            assert_eq!(pooled_buffer_reuses() - before, round * 10 + 9);
+        }
+    }
+
+    #[test]
+    fn into_page_keeps_the_buffer() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut manager = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        manager
+            .append_page(&Page::from_vec(vec![3; PAGESIZE], PAGESIZE).unwrap())
+            .unwrap();
+
+        let page = manager.read_page_pooled(0).unwrap().into_page();
+        assert!(page.read().iter().all(|&byte| byte == 3));
+        assert!(BUFFERS.with_borrow(|buffers| buffers.is_empty()));
+    }
+}
@@ -0,0 +1,306 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Where a PageManager keeps its bytes. The manager only ever seeks, reads and writes whole pages,
+// so the page level behaviour (reads past the end failing, torn pages) is the same for every
+// backend. Things only a real file can do (readahead, renaming, hole detection) go through file()
+pub trait Storage: Read + Write + Seek {
+    fn size(&self) -> Result<u64, io::Error>;
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error>;
+
+    fn sync_all(&mut self) -> Result<(), io::Error>;
+
+    fn sync_data(&mut self) -> Result<(), io::Error>;
+
+    fn file(&self) -> Option<&File> {
+        None
+    }
+}
+
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(Self { file })
+    }
+}
+
+// Advisory locking through flock, so it only keeps out others that lock the file too. Any number
+// of shared locks or a single exclusive one, held until the file is closed. A file already locked
+// the other way fails with WouldBlock instead of waiting for it. Files aren't locked elsewhere
+#[cfg(target_os = "linux")]
+pub(crate) fn try_lock(file: &File, shared: bool) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let operation = if shared { libc::LOCK_SH } else { libc::LOCK_EX };
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "File is locked by another PageManager",
+        ));
+    }
+    Err(err)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn try_lock(_file: &File, _shared: bool) -> Result<(), io::Error> {
+    Ok(())
+}
+
+impl From<File> for FileStorage {
+    fn from(file: File) -> Self {
+        Self { file }
+    }
+}
+
+impl Read for FileStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.file.read_vectored(bufs)
+    }
+}
+
+impl Write for FileStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FileStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Storage for FileStorage {
+    fn size(&self) -> Result<u64, io::Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        self.file.set_len(len)
+    }
+
+    fn sync_all(&mut self) -> Result<(), io::Error> {
+        self.file.sync_all()
+    }
+
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        self.file.sync_data()
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(&self.file)
+    }
+}
+
+// Everything lives in a Vec, so nothing survives the storage being dropped. Writing past the end
+// fills the gap with zeroes, like a hole in a file reads back
+#[derive(Default)]
+pub struct MemStorage {
+    data: Cursor<Vec<u8>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner()
+    }
+}
+
+impl Read for MemStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.data.read_vectored(bufs)
+    }
+}
+
+impl Write for MemStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemStorage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Storage for MemStorage {
+    fn size(&self) -> Result<u64, io::Error> {
+        Ok(self.data.get_ref().len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        self.data.get_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+// Stands in for a file the manager had to let go of and couldn't lock again, failing everything
+// so nothing reaches the file without the lock
+pub(crate) struct LostLock;
+
+fn lost_lock() -> io::Error {
+    io::Error::other("PageManager lost the lock on its file")
+}
+
+impl Read for LostLock {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(lost_lock())
+    }
+}
+
+impl Write for LostLock {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(lost_lock())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(lost_lock())
+    }
+}
+
+impl Seek for LostLock {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(lost_lock())
+    }
+}
+
+impl Storage for LostLock {
+    fn size(&self) -> Result<u64, io::Error> {
+        Err(lost_lock())
+    }
+
+    fn set_len(&mut self, _len: u64) -> Result<(), io::Error> {
+        Err(lost_lock())
+    }
+
+    fn sync_all(&mut self) -> Result<(), io::Error> {
+        Err(lost_lock())
+    }
+
+    fn sync_data(&mut self) -> Result<(), io::Error> {
+        Err(lost_lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{Page, PageManager, ShortRead};
+    use io::ErrorKind;
+    const PAGESIZE: usize = 16;
+
+    fn mem_manager() -> PageManager {
+        PageManager::with_storage(Box::new(MemStorage::new()), PAGESIZE)
+    }
+
+    #[test]
+    fn mem_storage_pages() {
+        let mut manager = mem_manager();
+        assert_eq!(manager.n_pages().unwrap(), 0);
+        for i in 0..3 {
+            let page = Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap();
+            assert_eq!(manager.append_page(&page).unwrap(), i as usize);
+        }
+        let page = Page::from_vec(vec![9; PAGESIZE], PAGESIZE).unwrap();
+        manager.write_page(1, &page).unwrap();
+
+        assert_eq!(manager.n_pages().unwrap(), 3);
+        assert_eq!(manager.read_page(0).unwrap().read(), &[0; PAGESIZE]);
+        assert_eq!(manager.read_page(1).unwrap().read(), &[9; PAGESIZE]);
+        assert_eq!(manager.read_page(2).unwrap().read(), &[2; PAGESIZE]);
+    }
+
+    #[test]
+    fn mem_storage_read_past_end() {
+        let mut manager = mem_manager();
+        let err = manager.read_page(0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        manager.append_page(&Page::new(PAGESIZE)).unwrap();
+        manager.storage.seek(SeekFrom::End(0)).unwrap();
+        manager.storage.write_all(&[1; 5]).unwrap();
+        let err = manager.read_page(1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        let short = err.get_ref().unwrap().downcast_ref::<ShortRead>().unwrap();
+        assert_eq!(short.readable, 5);
+    }
+
+    #[test]
+    fn mem_storage_write_past_end() {
+        let mut manager = mem_manager();
+        let page = Page::from_vec(vec![3; PAGESIZE], PAGESIZE).unwrap();
+        manager.write_page(2, &page).unwrap();
+
+        assert_eq!(manager.n_pages().unwrap(), 3);
+        assert_eq!(manager.read_page(0).unwrap().read(), &[0; PAGESIZE]);
+        assert_eq!(manager.read_page(2).unwrap().read(), &[3; PAGESIZE]);
+    }
+
+    #[test]
+    fn mem_storage_file_only_operations() {
+        let mut manager = mem_manager();
+        for i in 0..4 {
+            let page = Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap();
+            manager.append_page(&page).unwrap();
+        }
+        manager.prefetch_range(0, 4).unwrap();
+        manager.sync().unwrap();
+
+        let mut streamed = Vec::new();
+        let mut reader = manager.range_reader(1, 2).unwrap();
+        reader.read_to_end(&mut streamed).unwrap();
+        assert_eq!(&streamed[..PAGESIZE], &[1; PAGESIZE]);
+        assert_eq!(&streamed[PAGESIZE..], &[2; PAGESIZE]);
+
+        let err = manager.durable_publish("published.bin").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+}
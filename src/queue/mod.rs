@@ -0,0 +1,286 @@
+/*
+A FIFO queue of byte entries stored in a PageManager. Page 0 holds the queue metadata
+------------------------------------------------------------------------------------
+| head page (4 bytes) | head offset (2 bytes) | tail page (4 bytes) | free page (4 bytes) |
+------------------------------------------------------------------------------------
+
+Every other page is either a data page or on the free list. Data pages are chained from head to
+tail and look like
+--------------------------------------------------------
+| next page (4 bytes) | used (2 bytes) | frames | free |
+--------------------------------------------------------
+
+Entries are framed (see crate::framing) and appended left to right. Pushing writes at the used
+offset of the tail page, popping reads at the head offset of the head page. Once the head page
+is consumed it is pushed onto the free list, chained through its next page field, and reused
+before the file grows.
+*/
+
+use std::io::{self, ErrorKind};
+
+use crate::framing;
+use crate::page::{Page, PageManager};
+
+const META_PAGE: usize = 0;
+const NO_PAGE: u32 = u32::MAX;
+
+const META_HEAD: usize = 0;
+const META_HEAD_OFFSET: usize = 4;
+const META_TAIL: usize = 6;
+const META_FREE: usize = 10;
+const META_LEN: usize = 14;
+
+const DATA_NEXT: usize = 0;
+const DATA_USED: usize = 4;
+const DATA_START: usize = 6;
+
+pub struct PageQueue {
+    pm: PageManager,
+    head: usize,
+    head_offset: usize,
+    tail: usize,
+    free: u32,
+}
+
+fn get_u16(page: &Page, offset: usize) -> usize {
+    u16::from_be_bytes([page.read()[offset], page.read()[offset + 1]]) as usize
+}
+
+fn set_u16(page: &mut Page, offset: usize, value: usize) {
+    let value: u16 = value.try_into().expect("Value couldnt be converted to u16");
+    page.mutate()[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn get_u32(page: &Page, offset: usize) -> u32 {
+    u32::from_be_bytes(
+        page.read()[offset..offset + 4]
+            .try_into()
+            .expect("Slice is too small"),
+    )
+}
+
+fn set_u32(page: &mut Page, offset: usize, value: u32) {
+    page.mutate()[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn empty_data_page(page_size: usize) -> Page {
+    let mut page = Page::new(page_size);
+    set_u32(&mut page, DATA_NEXT, NO_PAGE);
+    set_u16(&mut page, DATA_USED, DATA_START);
+    page
+}
+
+impl PageQueue {
+    pub fn new(mut pm: PageManager) -> Result<Self, io::Error> {
+        if pm.page_size < META_LEN {
+            panic!(
+                "Tried creating a queue with page size {}, it needs at least {}",
+                pm.page_size, META_LEN
+            );
+        }
+
+        if pm.n_pages()? == 0 {
+            let mut queue = Self {
+                head: 1,
+                head_offset: DATA_START,
+                tail: 1,
+                free: NO_PAGE,
+                pm,
+            };
+            queue.write_meta()?;
+            queue.pm.append_page(&empty_data_page(queue.pm.page_size))?;
+            return Ok(queue);
+        }
+
+        let meta = pm.read_page(META_PAGE)?;
+        Ok(Self {
+            head: get_u32(&meta, META_HEAD) as usize,
+            head_offset: get_u16(&meta, META_HEAD_OFFSET),
+            tail: get_u32(&meta, META_TAIL) as usize,
+            free: get_u32(&meta, META_FREE),
+            pm,
+        })
+    }
+
+    fn write_meta(&mut self) -> Result<(), io::Error> {
+        let mut meta = Page::new(self.pm.page_size);
+        set_u32(&mut meta, META_HEAD, self.head as u32);
+        set_u16(&mut meta, META_HEAD_OFFSET, self.head_offset);
+        set_u32(&mut meta, META_TAIL, self.tail as u32);
+        set_u32(&mut meta, META_FREE, self.free);
+        self.pm.write_page(META_PAGE, &meta)?;
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> Result<usize, io::Error> {
+        let page = empty_data_page(self.pm.page_size);
+        if self.free == NO_PAGE {
+            return Ok(self.pm.append_page(&page)?);
+        }
+
+        let position = self.free as usize;
+        self.free = get_u32(&self.pm.read_page(position)?, DATA_NEXT);
+        self.pm.write_page(position, &page)?;
+        Ok(position)
+    }
+
+    fn release(&mut self, position: usize) -> Result<(), io::Error> {
+        let mut page = Page::new(self.pm.page_size);
+        set_u32(&mut page, DATA_NEXT, self.free);
+        self.pm.write_page(position, &page)?;
+        self.free = position as u32;
+        Ok(())
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let len = framing::frame_len(data.len());
+        if len > self.pm.page_size - DATA_START {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Tried pushing entry of size {} with page size {}",
+                    data.len(),
+                    self.pm.page_size
+                ),
+            ));
+        }
+
+        let mut tail = self.pm.read_page(self.tail)?;
+        let mut used = get_u16(&tail, DATA_USED);
+        if used + len > self.pm.page_size {
+            let next = self.allocate()?;
+            set_u32(&mut tail, DATA_NEXT, next as u32);
+            self.pm.write_page(self.tail, &tail)?;
+
+            self.tail = next;
+            tail = empty_data_page(self.pm.page_size);
+            used = DATA_START;
+        }
+
+        framing::write_frame(&mut tail.mutate()[used..], data)?;
+        set_u16(&mut tail, DATA_USED, used + len);
+        self.pm.write_page(self.tail, &tail)?;
+        self.write_meta()
+    }
+
+    pub fn pop(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        loop {
+            let mut head = self.pm.read_page(self.head)?;
+            let used = get_u16(&head, DATA_USED);
+
+            if self.head_offset < used {
+                let (data, _) = framing::read_frame(&head.read()[self.head_offset..used])?;
+                let data = data.to_vec();
+                self.head_offset += framing::frame_len(data.len());
+                self.write_meta()?;
+                return Ok(Some(data));
+            }
+
+            // The last page is drained, so start writing it from the beginning again
+            if self.head == self.tail {
+                if used != DATA_START {
+                    set_u16(&mut head, DATA_USED, DATA_START);
+                    self.pm.write_page(self.head, &head)?;
+                    self.head_offset = DATA_START;
+                    self.write_meta()?;
+                }
+                return Ok(None);
+            }
+
+            let consumed = self.head;
+            self.head = get_u32(&head, DATA_NEXT) as usize;
+            self.head_offset = DATA_START;
+            self.release(consumed)?;
+            self.write_meta()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const PAGESIZE: usize = 32;
+
+    fn entry(i: usize) -> Vec<u8> {
+        format!("entry-{:03}", i).into_bytes()
+    }
+
+    #[test]
+    fn fifo_across_pages() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("queue.bin");
+        let pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut queue = PageQueue::new(pm).unwrap();
+
+        assert_eq!(queue.pop().unwrap(), None);
+        for i in 0..10 {
+            queue.push(&entry(i)).unwrap();
+        }
+        assert!(queue.pm.n_pages().unwrap() > 2);
+
+        for i in 0..10 {
+            assert_eq!(queue.pop().unwrap(), Some(entry(i)));
+        }
+        assert_eq!(queue.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn consumed_pages_are_reused() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("queue.bin");
+        let pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut queue = PageQueue::new(pm).unwrap();
+
+        for i in 0..10 {
+            queue.push(&entry(i)).unwrap();
+        }
+
+        // Keep ten entries queued while cycling through many more than fit in the file
+        let mut n_pages = 0;
+        for round in 1..6 {
+            for i in 0..10 {
+                assert_eq!(queue.pop().unwrap(), Some(entry((round - 1) * 10 + i)));
+                queue.push(&entry(round * 10 + i)).unwrap();
+            }
+            if round == 1 {
+                n_pages = queue.pm.n_pages().unwrap();
+            }
+        }
+        assert_eq!(queue.pm.n_pages().unwrap(), n_pages);
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("queue.bin");
+
+        let pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut queue = PageQueue::new(pm).unwrap();
+        for i in 0..6 {
+            queue.push(&entry(i)).unwrap();
+        }
+        assert_eq!(queue.pop().unwrap(), Some(entry(0)));
+        drop(queue);
+
+        let pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut queue = PageQueue::new(pm).unwrap();
+        for i in 1..6 {
+            assert_eq!(queue.pop().unwrap(), Some(entry(i)));
+        }
+        assert_eq!(queue.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn entry_too_large() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("queue.bin");
+        let pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let mut queue = PageQueue::new(pm).unwrap();
+
+        let err = queue.push(&[0; PAGESIZE]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
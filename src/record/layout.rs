@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use crate::framing::FRAME_HEADER_LEN;
+
+// Ints are stored with Page::set_i32 and varchars with Page::set_string, so a varchar of up to
+// len bytes takes its length prefix on top
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Int,
+    VarChar(usize),
+}
+
+impl FieldType {
+    pub fn byte_len(&self) -> usize {
+        match self {
+            FieldType::Int => size_of::<i32>(),
+            FieldType::VarChar(len) => FRAME_HEADER_LEN + len,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    fields: Vec<(String, FieldType)>,
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_field(&mut self, name: &str, field_type: FieldType) -> &mut Self {
+        if self.field_type(name).is_some() {
+            panic!("Tried adding field {} to a schema twice", name);
+        }
+        self.fields.push((name.to_string(), field_type));
+        self
+    }
+
+    pub fn fields(&self) -> &[(String, FieldType)] {
+        &self.fields
+    }
+
+    pub fn field_type(&self, name: &str) -> Option<FieldType> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|&(_, field_type)| field_type)
+    }
+}
+
+// Fields are packed in schema order, each taking its type's full byte_len whatever it holds, so
+// every record of a schema has the same size
+#[derive(Debug, Clone)]
+pub struct Layout {
+    schema: Schema,
+    offsets: HashMap<String, usize>,
+    slot_size: usize,
+}
+
+impl Layout {
+    pub fn new(schema: Schema) -> Self {
+        let mut offsets = HashMap::with_capacity(schema.fields.len());
+        let mut slot_size = 0;
+        for (name, field_type) in &schema.fields {
+            offsets.insert(name.clone(), slot_size);
+            slot_size += field_type.byte_len();
+        }
+        Self {
+            schema,
+            offsets,
+            slot_size,
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn offset(&self, field: &str) -> Option<usize> {
+        self.offsets.get(field).copied()
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::Page;
+    use pretty_assertions::assert_eq;
+
+    fn people() -> Schema {
+        let mut schema = Schema::new();
+        schema
+            .add_field("id", FieldType::Int)
+            .add_field("name", FieldType::VarChar(10))
+            .add_field("age", FieldType::Int)
+            .add_field("city", FieldType::VarChar(3));
+        schema
+    }
+
+    #[test]
+    fn offsets_and_slot_size() {
+        // id 0..4, name 4..16 (2 byte length + 10), age 16..20, city 20..25
+        let layout = Layout::new(people());
+        assert_eq!(layout.offset("id"), Some(0));
+        assert_eq!(layout.offset("name"), Some(4));
+        assert_eq!(layout.offset("age"), Some(16));
+        assert_eq!(layout.offset("city"), Some(20));
+        assert_eq!(layout.offset("email"), None);
+        assert_eq!(layout.slot_size(), 25);
+        assert_eq!(
+            layout.schema().field_type("name"),
+            Some(FieldType::VarChar(10))
+        );
+    }
+
+    #[test]
+    fn fields_through_page_accessors() {
+        let layout = Layout::new(people());
+        let mut page = Page::new(2 * layout.slot_size());
+        let second = layout.slot_size();
+
+        page.set_i32(second + layout.offset("id").unwrap(), 7);
+        page.set_string(second + layout.offset("name").unwrap(), "ten bytes!")
+            .unwrap();
+        page.set_i32(second + layout.offset("age").unwrap(), 41);
+        page.set_string(second + layout.offset("city").unwrap(), "Osl")
+            .unwrap();
+
+        assert_eq!(page.get_i32(second + layout.offset("id").unwrap()), 7);
+        assert_eq!(
+            page.get_string(second + layout.offset("name").unwrap())
+                .unwrap(),
+            "ten bytes!"
+        );
+        assert_eq!(page.get_i32(second + layout.offset("age").unwrap()), 41);
+        assert_eq!(
+            page.get_string(second + layout.offset("city").unwrap())
+                .unwrap(),
+            "Osl"
+        );
+        assert_eq!(&page.read()[..second], &vec![0; second][..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn duplicate_field() {
+        people().add_field("age", FieldType::Int);
+    }
+
+    #[test]
+    fn empty_schema() {
+        let layout = Layout::new(Schema::new());
+        assert_eq!(layout.slot_size(), 0);
+        assert_eq!(layout.offset("id"), None);
+    }
+}
@@ -0,0 +1,416 @@
+/*
+A slotted page for variable-length records
+------------------------------------------------------------------------------------------
+| slot count (2 bytes) | free end (2 bytes) | slot 0 | slot 1 | ... | free | records |
+------------------------------------------------------------------------------------------
+
+Each slot is an offset (2 bytes) and a length (2 bytes) pointing at its record. Slots grow from
+the left and records from the right, the free end being where the leftmost record starts. A
+deleted slot is a tombstone with offset 0, which no record can have since the header lives there.
+Tombstones are handed out again before the directory grows, so slot numbers stay stable for the
+records that remain. The bytes of deleted records are reclaimed by compacting the records once an
+insert doesn't fit otherwise.
+
+A TableScan walks the records of every page of a table file in page and slot order, reading the
+fields of the one it stands on through a Layout.
+*/
+
+use std::io;
+
+use crate::framing;
+use crate::page::{Page, PageManager};
+
+mod layout;
+
+pub use layout::{FieldType, Layout, Schema};
+
+const SLOT_COUNT: usize = 0;
+const FREE_END: usize = 2;
+const HEADER_LEN: usize = 4;
+const SLOT_LEN: usize = 4;
+const TOMBSTONE: usize = 0;
+
+pub struct RecordPage {
+    page: Page,
+}
+
+impl RecordPage {
+    pub fn new(page_size: usize) -> Self {
+        if page_size > u16::MAX as usize {
+            panic!(
+                "Tried creating a record page of size {}, offsets only go up to {}",
+                page_size,
+                u16::MAX
+            );
+        }
+        let mut page = Self {
+            page: Page::new(page_size),
+        };
+        page.set_u16(FREE_END, page_size);
+        page
+    }
+
+    // Takes over a page previously laid out by a RecordPage
+    pub fn from_page(page: Page) -> Self {
+        Self { page }
+    }
+
+    pub fn page(&self) -> &Page {
+        &self.page
+    }
+
+    pub fn into_page(self) -> Page {
+        self.page
+    }
+
+    fn get_u16(&self, offset: usize) -> usize {
+        u16::from_be_bytes([self.page.read()[offset], self.page.read()[offset + 1]]) as usize
+    }
+
+    fn set_u16(&mut self, offset: usize, value: usize) {
+        let value: u16 = value.try_into().expect("Value couldnt be converted to u16");
+        self.page.mutate()[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.get_u16(SLOT_COUNT)
+    }
+
+    fn free_end(&self) -> usize {
+        self.get_u16(FREE_END)
+    }
+
+    fn slot_position(slot: usize) -> usize {
+        HEADER_LEN + slot * SLOT_LEN
+    }
+
+    // Offset and length of the record in slot
+    fn slot(&self, slot: usize) -> (usize, usize) {
+        let position = Self::slot_position(slot);
+        (self.get_u16(position), self.get_u16(position + 2))
+    }
+
+    fn set_slot(&mut self, slot: usize, offset: usize, len: usize) {
+        let position = Self::slot_position(slot);
+        self.set_u16(position, offset);
+        self.set_u16(position + 2, len);
+    }
+
+    // Contiguous bytes between the slot directory and the records
+    pub fn free_space(&self) -> usize {
+        self.free_end() - Self::slot_position(self.slot_count())
+    }
+
+    fn live_bytes(&self) -> usize {
+        (0..self.slot_count())
+            .map(|slot| self.slot(slot))
+            .filter(|&(offset, _)| offset != TOMBSTONE)
+            .map(|(_, len)| len)
+            .sum()
+    }
+
+    // Returns the slot the record went into, or None if it doesn't fit even after compacting
+    pub fn insert(&mut self, bytes: &[u8]) -> Option<usize> {
+        let count = self.slot_count();
+        let reused = (0..count).find(|&slot| self.slot(slot).0 == TOMBSTONE);
+        let directory_growth = if reused.is_some() { 0 } else { SLOT_LEN };
+        let needed = bytes.len() + directory_growth;
+
+        if needed > self.free_space() {
+            let reclaimable =
+                self.page.read().len() - Self::slot_position(count) - self.live_bytes();
+            if needed > reclaimable {
+                return None;
+            }
+            self.compact();
+        }
+
+        let offset = self.free_end() - bytes.len();
+        self.page.mutate()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.set_u16(FREE_END, offset);
+        let slot = reused.unwrap_or(count);
+        if reused.is_none() {
+            self.set_u16(SLOT_COUNT, count + 1);
+        }
+        self.set_slot(slot, offset, bytes.len());
+        Some(slot)
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&[u8]> {
+        if slot >= self.slot_count() {
+            return None;
+        }
+        match self.slot(slot) {
+            (TOMBSTONE, _) => None,
+            (offset, len) => Some(&self.page.read()[offset..offset + len]),
+        }
+    }
+
+    // Deleting a slot that is out of range or already deleted does nothing
+    pub fn delete(&mut self, slot: usize) {
+        if slot >= self.slot_count() {
+            return;
+        }
+        self.set_slot(slot, TOMBSTONE, 0);
+    }
+
+    // Moves the live records flush against the end of the page, rightmost first so none is
+    // overwritten before it has moved
+    fn compact(&mut self) {
+        let mut slots: Vec<(usize, usize, usize)> = (0..self.slot_count())
+            .map(|slot| (slot, self.slot(slot)))
+            .filter(|&(_, (offset, _))| offset != TOMBSTONE)
+            .map(|(slot, (offset, len))| (slot, offset, len))
+            .collect();
+        slots.sort_by_key(|&(_, offset, _)| std::cmp::Reverse(offset));
+
+        let mut end = self.page.read().len();
+        for (slot, offset, len) in slots {
+            let new_offset = end - len;
+            self.page
+                .mutate()
+                .copy_within(offset..offset + len, new_offset);
+            self.set_slot(slot, new_offset, len);
+            end = new_offset;
+        }
+        self.set_u16(FREE_END, end);
+    }
+}
+
+pub struct TableScan<'a> {
+    pm: &'a mut PageManager,
+    layout: &'a Layout,
+    // Page position and record page being scanned, None before the first next
+    current: Option<(usize, RecordPage)>,
+    // The slot next stopped at, next looks after it
+    slot: Option<usize>,
+}
+
+impl<'a> TableScan<'a> {
+    pub fn new(pm: &'a mut PageManager, layout: &'a Layout) -> Self {
+        Self {
+            pm,
+            layout,
+            current: None,
+            slot: None,
+        }
+    }
+
+    // Moves to the next live record, returning false once past the last one. A cursor rather
+    // than an Iterator, the fields are read from the scan itself
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool, io::Error> {
+        loop {
+            let position = match &self.current {
+                Some((position, page)) => {
+                    let start = self.slot.map_or(0, |slot| slot + 1);
+                    if let Some(slot) = (start..page.slot_count()).find(|&s| page.get(s).is_some())
+                    {
+                        self.slot = Some(slot);
+                        return Ok(true);
+                    }
+                    position + 1
+                }
+                None => 0,
+            };
+            if position >= self.pm.n_pages()? {
+                self.slot = None;
+                return Ok(false);
+            }
+            self.current = Some((
+                position,
+                RecordPage::from_page(self.pm.read_page(position)?),
+            ));
+            self.slot = None;
+        }
+    }
+
+    // Page position and slot of the current record
+    pub fn current(&self) -> Option<(usize, usize)> {
+        Some((self.current.as_ref()?.0, self.slot?))
+    }
+
+    fn field(&self, name: &str) -> &[u8] {
+        let record = match (&self.current, self.slot) {
+            (Some((_, page)), Some(slot)) => page.get(slot).expect("Scan stopped at a live slot"),
+            _ => panic!("Tried reading field {} with the scan not on a record", name),
+        };
+        let offset = self
+            .layout
+            .offset(name)
+            .unwrap_or_else(|| panic!("Field {} is not in the layout", name));
+        record.get(offset..).unwrap_or(&[])
+    }
+
+    pub fn get_int(&self, name: &str) -> Result<i32, io::Error> {
+        let bytes = self.field(name);
+        let bytes = bytes.get(..size_of::<i32>()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Record is too short for int field {}", name),
+            )
+        })?;
+        Ok(i32::from_be_bytes(
+            bytes.try_into().expect("Slice is 4 bytes"),
+        ))
+    }
+
+    pub fn get_string(&self, name: &str) -> Result<String, io::Error> {
+        let (bytes, _) = framing::read_frame(self.field(name))?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    const PAGESIZE: usize = 64;
+
+    fn record(i: usize) -> Vec<u8> {
+        format!("rec{}", i).into_bytes()
+    }
+
+    #[test]
+    fn insert_until_full() {
+        let mut page = RecordPage::new(PAGESIZE);
+        assert_eq!(page.free_space(), PAGESIZE - HEADER_LEN);
+
+        // Every record takes 4 bytes plus its slot, so 7 fit in the 60 free bytes
+        for i in 0..7 {
+            assert_eq!(page.insert(&record(i)), Some(i));
+        }
+        assert_eq!(page.free_space(), 4);
+        assert_eq!(page.insert(&record(7)), None);
+        assert_eq!(page.insert(&[]), Some(7));
+        assert_eq!(page.insert(&[]), None);
+
+        for i in 0..7 {
+            assert_eq!(page.get(i), Some(&record(i)[..]));
+        }
+        assert_eq!(page.get(7), Some(&[][..]));
+        assert_eq!(page.get(8), None);
+    }
+
+    #[test]
+    fn delete_and_reuse_slot() {
+        let mut page = RecordPage::new(PAGESIZE);
+        for i in 0..3 {
+            page.insert(&record(i)).unwrap();
+        }
+
+        page.delete(1);
+        assert_eq!(page.get(1), None);
+        assert_eq!(page.get(2), Some(&b"rec2"[..]));
+        page.delete(1);
+        page.delete(10);
+
+        assert_eq!(page.insert(b"again"), Some(1));
+        assert_eq!(page.get(1), Some(&b"again"[..]));
+        assert_eq!(page.slot_count(), 3);
+        assert_eq!(page.insert(b"next"), Some(3));
+    }
+
+    #[test]
+    fn insert_compacts_deleted_bytes() {
+        let mut page = RecordPage::new(PAGESIZE);
+        for i in 0..7 {
+            page.insert(&record(i)).unwrap();
+        }
+        page.delete(2);
+        page.delete(4);
+        assert_eq!(page.free_space(), 4);
+
+        // Only fits once the bytes of both deleted records are put back together
+        assert_eq!(page.insert(b"longer"), Some(2));
+        assert_eq!(page.get(2), Some(&b"longer"[..]));
+        for i in [0, 1, 3, 5, 6] {
+            assert_eq!(page.get(i), Some(&record(i)[..]));
+        }
+        assert_eq!(page.get(4), None);
+        assert_eq!(page.free_space(), 6);
+        assert_eq!(page.insert(b"toolong"), None);
+    }
+
+    fn people() -> Layout {
+        let mut schema = Schema::new();
+        schema
+            .add_field("id", FieldType::Int)
+            .add_field("name", FieldType::VarChar(6));
+        Layout::new(schema)
+    }
+
+    fn row(layout: &Layout, id: i32, name: &str) -> Vec<u8> {
+        let mut page = Page::new(layout.slot_size());
+        page.set_i32(layout.offset("id").unwrap(), id);
+        page.set_string(layout.offset("name").unwrap(), name)
+            .unwrap();
+        page.read().to_vec()
+    }
+
+    #[test]
+    fn scan_across_pages() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let layout = people();
+
+        // Rows are 12 bytes plus a 4 byte slot, so 3 fit in a page
+        let mut expected = Vec::new();
+        let mut page = RecordPage::new(PAGESIZE);
+        for id in 0..5 {
+            let name = format!("row{}", id);
+            if page.insert(&row(&layout, id, &name)).is_none() {
+                pm.append_page(page.page()).unwrap();
+                page = RecordPage::new(PAGESIZE);
+                page.insert(&row(&layout, id, &name)).unwrap();
+            }
+            expected.push((id, name));
+        }
+        // A deleted row is skipped
+        let gone = page.insert(&row(&layout, 9, "gone")).unwrap();
+        page.delete(gone);
+        pm.append_page(page.page()).unwrap();
+        // As is a page with nothing on it
+        pm.append_page(RecordPage::new(PAGESIZE).page()).unwrap();
+        assert_eq!(pm.n_pages().unwrap(), 3);
+
+        let mut scan = TableScan::new(&mut pm, &layout);
+        let mut read = Vec::new();
+        while scan.next().unwrap() {
+            read.push((
+                scan.get_int("id").unwrap(),
+                scan.get_string("name").unwrap(),
+            ));
+        }
+        assert_eq!(read, expected);
+        assert_eq!(scan.current(), None);
+        assert!(!scan.next().unwrap());
+    }
+
+    #[test]
+    fn scan_empty_table() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("testfile.bin");
+        let mut pm = PageManager::new(file_path.to_str().unwrap(), PAGESIZE).unwrap();
+        let layout = people();
+        let mut scan = TableScan::new(&mut pm, &layout);
+        assert!(!scan.next().unwrap());
+    }
+
+    #[test]
+    fn survives_round_trip_through_page() {
+        let mut page = RecordPage::new(PAGESIZE);
+        page.insert(b"kept").unwrap();
+        page.insert(b"gone").unwrap();
+        page.delete(1);
+
+        let page = RecordPage::from_page(page.into_page());
+        assert_eq!(page.get(0), Some(&b"kept"[..]));
+        assert_eq!(page.get(1), None);
+        assert_eq!(page.slot_count(), 2);
+    }
+}
@@ -0,0 +1,302 @@
+/*
+Undo recovery after a crash. The buffer pool only ever writes a page once the log records
+describing it are on disk, so every uncommitted change that reached a page can be found in the
+log with the bytes it replaced. Recovery walks the log from the newest record back and puts those
+bytes back for every transaction that has neither a commit nor a rollback record. A rollback
+record is only logged once the restored pages are on disk, so a transaction that has one is
+finished just like a committed one, and undoing it again could overwrite what later transactions
+wrote. Recovery logs rollback records for what it undid the same way, after writing the pages.
+Undoing twice writes the same bytes, so a crash during recovery just means running it again.
+
+A checkpoint record marks a point where every page change so far was on disk, so the walk stops
+at the newest one. Transactions that were active at the checkpoint are the exception, for those
+the walk goes on until it has seen their begin records.
+*/
+
+use std::collections::HashSet;
+use std::io;
+
+use crate::cache::{BufferError, BufferPool};
+use crate::log::{LogManager, LogRecord};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    // Transactions that were undone, newest first
+    pub undone: Vec<i32>,
+    // Log records read before the walk stopped
+    pub scanned: usize,
+}
+
+pub struct RecoveryManager<'a> {
+    pool: &'a mut BufferPool,
+}
+
+struct Undo {
+    tx_id: i32,
+    position: usize,
+    offset: usize,
+    old: Vec<u8>,
+}
+
+impl<'a> RecoveryManager<'a> {
+    pub fn new(pool: &'a mut BufferPool) -> Self {
+        Self { pool }
+    }
+
+    fn log(&mut self) -> &mut LogManager {
+        self.pool
+            .log()
+            .expect("Recovery needs a buffer pool with a log")
+    }
+
+    // The rollback records and the restored pages are on disk by the time this returns
+    pub fn recover(&mut self) -> Result<RecoveryReport, BufferError> {
+        // Committed or rolled back
+        let mut finished = HashSet::new();
+        let mut undos = Vec::new();
+        let mut scanned = 0;
+        // Set once past a checkpoint, to the transactions whose begin records are still ahead
+        let mut waiting: Option<HashSet<i32>> = None;
+        for record in self.log().iter() {
+            if waiting.as_ref().is_some_and(HashSet::is_empty) {
+                break;
+            }
+            scanned += 1;
+            match LogRecord::deserialize(&record?).map_err(io::Error::from)? {
+                LogRecord::Checkpoint(active) if waiting.is_none() => {
+                    waiting = Some(active.into_iter().collect());
+                }
+                LogRecord::Begin(tx_id) => {
+                    if let Some(waiting) = waiting.as_mut() {
+                        waiting.remove(&tx_id);
+                    }
+                }
+                LogRecord::Commit(tx_id) | LogRecord::Rollback(tx_id) => {
+                    finished.insert(tx_id);
+                }
+                LogRecord::Update {
+                    tx_id,
+                    page,
+                    offset,
+                    old,
+                    ..
+                } if !finished.contains(&tx_id)
+                    && waiting
+                        .as_ref()
+                        .is_none_or(|waiting| waiting.contains(&tx_id)) =>
+                {
+                    undos.push(Undo {
+                        tx_id,
+                        position: page as usize,
+                        offset: offset as usize,
+                        old,
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        let mut undone: Vec<i32> = Vec::new();
+        for undo in &undos {
+            if !undone.contains(&undo.tx_id) {
+                undone.push(undo.tx_id);
+            }
+        }
+        // Newest first, so where one transaction changed the same bytes twice the oldest image
+        // is the one left behind
+        for undo in undos {
+            let frame = self.pool.pin(undo.position)?;
+            let page = self
+                .pool
+                .page_mut(frame)
+                .expect("Pinned frames hold a page");
+            page.mutate()[undo.offset..undo.offset + undo.old.len()].copy_from_slice(&undo.old);
+            self.pool.mark_modified(frame, undo.tx_id, 0);
+            self.pool.unpin(frame, true);
+        }
+        self.pool.flush_all()?;
+        for &tx_id in &undone {
+            self.log().append_record(&LogRecord::Rollback(tx_id))?;
+        }
+        self.log().flush()?;
+        Ok(RecoveryReport { undone, scanned })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{Page, PageManager};
+    use crate::tx::TransactionManager;
+    use std::path::Path;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 16;
+    const LOG_PAGESIZE: usize = 128;
+
+    fn open_pool(dir: &Path, n_frames: usize) -> BufferPool {
+        let pm = PageManager::new(dir.join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        let log_path = dir.join("logfile.bin");
+        let log = LogManager::new(log_path.to_str().unwrap(), LOG_PAGESIZE).unwrap();
+        BufferPool::with_log(pm, log, n_frames)
+    }
+
+    fn read_page(dir: &Path, position: usize) -> Vec<u8> {
+        let mut pm =
+            PageManager::new(dir.join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        pm.read_page(position).unwrap().read().to_vec()
+    }
+
+    #[test]
+    fn undo_uncommitted_after_crash() {
+        let dir = tempdir().unwrap();
+        let mut pm =
+            PageManager::new(dir.path().join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        for i in 0..3 {
+            pm.append_page(&Page::from_vec(vec![i; PAGESIZE], PAGESIZE).unwrap())
+                .unwrap();
+        }
+        drop(pm);
+
+        let mut pool = open_pool(dir.path(), 1);
+        let mut txs = TransactionManager::new();
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(0, 0, b"done").unwrap();
+        tx.commit().unwrap();
+
+        // Begun but never finished. Touching page 2 forces pages 0 and 1 out with the
+        // uncommitted bytes in them
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(0, 4, b"lost").unwrap();
+        tx.write(1, 0, b"lost").unwrap();
+        tx.write(1, 2, b"again").unwrap();
+        tx.write(2, 0, b"lost").unwrap();
+        // The crash, nothing left in the pool is written back
+        drop(pool);
+        assert_eq!(&read_page(dir.path(), 0)[..8], b"donelost");
+        assert_eq!(&read_page(dir.path(), 1)[..7], b"loagain");
+
+        let mut pool = open_pool(dir.path(), 1);
+        let report = RecoveryManager::new(&mut pool).recover().unwrap();
+        assert_eq!(report.undone, vec![2]);
+        drop(pool);
+
+        let mut expected = [0; PAGESIZE];
+        expected[..4].copy_from_slice(b"done");
+        assert_eq!(read_page(dir.path(), 0), expected);
+        assert_eq!(read_page(dir.path(), 1), [1; PAGESIZE]);
+        assert_eq!(read_page(dir.path(), 2), [2; PAGESIZE]);
+
+        // Running it again finds the rollback record and has nothing left to undo
+        let mut pool = open_pool(dir.path(), 1);
+        let report = RecoveryManager::new(&mut pool).recover().unwrap();
+        assert_eq!(report.undone, Vec::<i32>::new());
+        drop(pool);
+        assert_eq!(read_page(dir.path(), 0), expected);
+    }
+
+    #[test]
+    fn skip_rolled_back() {
+        let dir = tempdir().unwrap();
+        let mut pm =
+            PageManager::new(dir.path().join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        pm.append_page(&Page::new(PAGESIZE)).unwrap();
+        drop(pm);
+
+        let mut pool = open_pool(dir.path(), 1);
+        let mut txs = TransactionManager::new();
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(0, 0, b"XXXX").unwrap();
+        tx.rollback().unwrap();
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(0, 0, b"BBBB").unwrap();
+        tx.commit().unwrap();
+        pool.flush_all().unwrap();
+        drop(pool);
+
+        // Undoing the rolled back transaction again would put back the zeroes it replaced
+        let mut pool = open_pool(dir.path(), 1);
+        let report = RecoveryManager::new(&mut pool).recover().unwrap();
+        assert_eq!(report.undone, Vec::<i32>::new());
+        drop(pool);
+        assert_eq!(&read_page(dir.path(), 0)[..4], b"BBBB");
+    }
+
+    #[test]
+    fn stop_at_checkpoint() {
+        let dir = tempdir().unwrap();
+        let mut pm =
+            PageManager::new(dir.path().join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        for _ in 0..3 {
+            pm.append_page(&Page::new(PAGESIZE)).unwrap();
+        }
+        drop(pm);
+
+        let mut pool = open_pool(dir.path(), 1);
+        let mut txs = TransactionManager::new();
+        for _ in 0..3 {
+            let mut tx = txs.begin(&mut pool).unwrap();
+            tx.write(0, 0, b"old").unwrap();
+            tx.commit().unwrap();
+        }
+        pool.checkpoint(&[]).unwrap();
+
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(1, 0, b"new").unwrap();
+        tx.write(2, 0, b"new").unwrap();
+        drop(pool);
+        assert_eq!(&read_page(dir.path(), 1)[..3], b"new");
+
+        // Only the update flushed ahead of page 1 made it to the log, so the walk reads it, the
+        // begin record and the checkpoint
+        let mut pool = open_pool(dir.path(), 1);
+        let report = RecoveryManager::new(&mut pool).recover().unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                undone: vec![4],
+                scanned: 3,
+            }
+        );
+        drop(pool);
+        assert_eq!(read_page(dir.path(), 1), [0; PAGESIZE]);
+        assert_eq!(&read_page(dir.path(), 0)[..3], b"old");
+    }
+
+    #[test]
+    fn scan_past_checkpoint_for_active() {
+        let dir = tempdir().unwrap();
+        let mut pm =
+            PageManager::new(dir.path().join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        for _ in 0..2 {
+            pm.append_page(&Page::new(PAGESIZE)).unwrap();
+        }
+        drop(pm);
+
+        let mut pool = open_pool(dir.path(), 2);
+        let mut txs = TransactionManager::new();
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(1, 0, b"done").unwrap();
+        tx.commit().unwrap();
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(0, 0, b"open").unwrap();
+        assert_eq!(tx.tx_id(), 2);
+
+        // Transaction 2 is still going when the checkpoint writes its page out
+        pool.checkpoint(&[2]).unwrap();
+        drop(pool);
+        assert_eq!(&read_page(dir.path(), 0)[..4], b"open");
+
+        let mut pool = open_pool(dir.path(), 2);
+        let report = RecoveryManager::new(&mut pool).recover().unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                undone: vec![2],
+                scanned: 3,
+            }
+        );
+        drop(pool);
+        assert_eq!(read_page(dir.path(), 0), [0; PAGESIZE]);
+        assert_eq!(&read_page(dir.path(), 1)[..4], b"done");
+    }
+}
@@ -0,0 +1,342 @@
+/*
+Transactions over a BufferPool and the LogManager it holds. Every change is logged as an update
+record holding the bytes it replaced before the page is touched, so a transaction can be undone
+from the log alone. Rolling back walks the log from the newest record back to the transaction's
+begin record and writes the old bytes back.
+*/
+
+use std::io;
+
+use crate::cache::{BufferError, BufferPool};
+use crate::log::{LogManager, LogRecord};
+use crate::page::PageError;
+
+pub struct TransactionManager {
+    next_tx_id: i32,
+}
+
+impl Default for TransactionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransactionManager {
+    pub fn new() -> Self {
+        Self { next_tx_id: 1 }
+    }
+
+    pub fn begin<'a>(&mut self, pool: &'a mut BufferPool) -> Result<Transaction<'a>, io::Error> {
+        let tx_id = self.next_tx_id;
+        self.next_tx_id = tx_id.checked_add(1).expect("Ran out of transaction ids");
+        Transaction::begin(pool, tx_id)
+    }
+}
+
+pub struct Transaction<'a> {
+    tx_id: i32,
+    pool: &'a mut BufferPool,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin(pool: &'a mut BufferPool, tx_id: i32) -> Result<Self, io::Error> {
+        let mut tx = Self { tx_id, pool };
+        tx.log().append_record(&LogRecord::Begin(tx_id))?;
+        Ok(tx)
+    }
+
+    pub fn tx_id(&self) -> i32 {
+        self.tx_id
+    }
+
+    fn log(&mut self) -> &mut LogManager {
+        self.pool
+            .log()
+            .expect("Transactions need a buffer pool with a log")
+    }
+
+    // Overwrites the bytes at offset in the page at position, logging what was there first
+    pub fn write(
+        &mut self,
+        position: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), BufferError> {
+        let frame = self.pool.pin(position)?;
+        let written = self.write_pinned(frame, position, offset, bytes);
+        self.pool.unpin(frame, written.is_ok());
+        written
+    }
+
+    fn write_pinned(
+        &mut self,
+        frame: usize,
+        position: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), BufferError> {
+        let page = self.pool.page(frame).expect("Pinned frames hold a page");
+        let page_size = page.read().len();
+        let end = offset
+            .checked_add(bytes.len())
+            .filter(|&end| end <= page_size)
+            .ok_or(PageError::OutOfBounds {
+                offset,
+                len: bytes.len(),
+                page_size,
+            })?;
+        let old = page.read()[offset..end].to_vec();
+
+        let record = LogRecord::Update {
+            tx_id: self.tx_id,
+            page: position
+                .try_into()
+                .expect("Page position doesn't fit in u32"),
+            offset: offset.try_into().expect("Offset doesn't fit in u16"),
+            old,
+            new: bytes.to_vec(),
+        };
+        let lsn = self.log().append_record(&record)?;
+
+        let page = self
+            .pool
+            .page_mut(frame)
+            .expect("Pinned frames hold a page");
+        page.mutate()[offset..end].copy_from_slice(bytes);
+        self.pool.mark_modified(frame, self.tx_id, lsn);
+        Ok(())
+    }
+
+    // Returns once the changed pages and then the commit record are on disk. Recovery only
+    // undoes, it has nothing to redo a committed change from, so the pages have to be written
+    // here rather than whenever the pool gets around to it. A crash in between leaves no commit
+    // record, so recovery undoes the changes again
+    pub fn commit(mut self) -> Result<(), BufferError> {
+        self.pool.flush_tx(self.tx_id)?;
+        let record = LogRecord::Commit(self.tx_id);
+        let log = self.log();
+        let lsn = log.append_record(&record)?;
+        Ok(log.flush_since_lsn(lsn)?)
+    }
+
+    pub fn rollback(mut self) -> Result<(), BufferError> {
+        let tx_id = self.tx_id;
+        let mut undos = Vec::new();
+        for record in self.log().iter() {
+            let record = record?;
+            match LogRecord::deserialize(&record).map_err(io::Error::from)? {
+                LogRecord::Begin(id) if id == tx_id => break,
+                LogRecord::Update {
+                    tx_id: id,
+                    page,
+                    offset,
+                    old,
+                    ..
+                } if id == tx_id => undos.push((page as usize, offset as usize, old)),
+                _ => {}
+            }
+        }
+
+        // The old bytes come from records already in the log, so the restored pages need no
+        // new record on disk before they are written
+        for (position, offset, old) in undos {
+            let frame = self.pool.pin(position)?;
+            let page = self
+                .pool
+                .page_mut(frame)
+                .expect("Pinned frames hold a page");
+            page.mutate()[offset..offset + old.len()].copy_from_slice(&old);
+            self.pool.mark_modified(frame, tx_id, 0);
+            self.pool.unpin(frame, true);
+        }
+
+        // Recovery takes the rollback record to mean the transaction is fully undone, so it only
+        // goes in once the restored pages are on disk. A crash before that has recovery undo
+        // the transaction again, which writes the same bytes
+        self.pool.flush_tx(tx_id)?;
+        let log = self.log();
+        let lsn = log.append_record(&LogRecord::Rollback(tx_id))?;
+        Ok(log.flush_since_lsn(lsn)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::{MemStorage, Page, PageManager, Storage};
+    use std::cell::RefCell;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+    use tempfile::tempdir;
+    const PAGESIZE: usize = 16;
+    const LOG_PAGESIZE: usize = 128;
+
+    fn pool(dir: &std::path::Path) -> BufferPool {
+        let mut pm =
+            PageManager::new(dir.join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        for _ in 0..3 {
+            pm.append_page(&Page::new(PAGESIZE)).unwrap();
+        }
+        let log_path = dir.join("logfile.bin");
+        let log = LogManager::new(log_path.to_str().unwrap(), LOG_PAGESIZE).unwrap();
+        BufferPool::with_log(pm, log, 2)
+    }
+
+    fn read(pool: &mut BufferPool, position: usize) -> Vec<u8> {
+        let frame = pool.pin(position).unwrap();
+        let bytes = pool.page(frame).unwrap().read().to_vec();
+        pool.unpin(frame, false);
+        bytes
+    }
+
+    #[test]
+    fn commit_and_rollback() {
+        let dir = tempdir().unwrap();
+        let mut pool = pool(dir.path());
+        let mut txs = TransactionManager::new();
+
+        let mut tx = txs.begin(&mut pool).unwrap();
+        assert_eq!(tx.tx_id(), 1);
+        tx.write(0, 0, b"kept").unwrap();
+        tx.write(1, 4, b"kept").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(pool.log().unwrap().metrics().unflushed_records, 0);
+
+        let mut tx = txs.begin(&mut pool).unwrap();
+        assert_eq!(tx.tx_id(), 2);
+        tx.write(0, 2, b"gone").unwrap();
+        tx.write(2, 0, b"gone").unwrap();
+        // Forces the earlier pages out, so some undos have to read them back in
+        tx.write(1, 0, b"gone").unwrap();
+        tx.write(0, 2, b"again").unwrap();
+        tx.rollback().unwrap();
+
+        assert_eq!(&read(&mut pool, 0)[..8], b"kept\0\0\0\0");
+        assert_eq!(&read(&mut pool, 1)[..8], b"\0\0\0\0kept");
+        assert_eq!(read(&mut pool, 2), [0; PAGESIZE]);
+    }
+
+    #[test]
+    fn commit_survives_crash() {
+        let dir = tempdir().unwrap();
+        let mut pool = pool(dir.path());
+        let mut txs = TransactionManager::new();
+
+        // Two frames and two pages, so nothing gets evicted on the way
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(0, 0, b"kept").unwrap();
+        tx.write(1, 4, b"kept").unwrap();
+        tx.commit().unwrap();
+        let mut tx = txs.begin(&mut pool).unwrap();
+        tx.write(1, 0, b"open").unwrap();
+        // The crash, nothing left in the pool is written back
+        drop(pool);
+
+        let mut pm =
+            PageManager::new(dir.path().join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        assert_eq!(&pm.read_page(0).unwrap().read()[..4], b"kept");
+        assert_eq!(&pm.read_page(1).unwrap().read()[..8], b"\0\0\0\0kept");
+    }
+
+    // Records which file was synced, in order
+    struct SyncOrder {
+        inner: Box<dyn Storage>,
+        name: &'static str,
+        syncs: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl SyncOrder {
+        fn wrap(
+            storage: &mut Box<dyn Storage>,
+            name: &'static str,
+            syncs: &Rc<RefCell<Vec<&'static str>>>,
+        ) {
+            let inner = std::mem::replace(storage, Box::new(MemStorage::new()));
+            *storage = Box::new(SyncOrder {
+                inner,
+                name,
+                syncs: Rc::clone(syncs),
+            });
+        }
+    }
+
+    impl Read for SyncOrder {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for SyncOrder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Seek for SyncOrder {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    impl Storage for SyncOrder {
+        fn size(&self) -> Result<u64, io::Error> {
+            self.inner.size()
+        }
+
+        fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+            self.inner.set_len(len)
+        }
+
+        fn sync_all(&mut self) -> Result<(), io::Error> {
+            self.syncs.borrow_mut().push(self.name);
+            self.inner.sync_all()
+        }
+
+        fn sync_data(&mut self) -> Result<(), io::Error> {
+            self.syncs.borrow_mut().push(self.name);
+            self.inner.sync_data()
+        }
+    }
+
+    #[test]
+    fn commit_syncs_pages_before_log() {
+        let dir = tempdir().unwrap();
+        let syncs = Rc::new(RefCell::new(Vec::new()));
+        let mut pm =
+            PageManager::new(dir.path().join("testfile.bin").to_str().unwrap(), PAGESIZE).unwrap();
+        pm.append_page(&Page::new(PAGESIZE)).unwrap();
+        SyncOrder::wrap(&mut pm.storage, "data", &syncs);
+        let log_path = dir.path().join("logfile.bin");
+        let mut log = LogManager::new(log_path.to_str().unwrap(), LOG_PAGESIZE).unwrap();
+        SyncOrder::wrap(&mut log.log.storage, "log", &syncs);
+        let mut pool = BufferPool::with_log(pm, log, 2);
+
+        let mut tx = TransactionManager::new().begin(&mut pool).unwrap();
+        tx.write(0, 0, b"kept").unwrap();
+        assert!(syncs.borrow().is_empty());
+        tx.commit().unwrap();
+
+        // The update record goes ahead of the page, and the commit record only after it
+        assert_eq!(*syncs.borrow(), ["log", "data", "log"]);
+    }
+
+    #[test]
+    fn write_out_of_bounds() {
+        let dir = tempdir().unwrap();
+        let mut pool = pool(dir.path());
+        let mut tx = TransactionManager::new().begin(&mut pool).unwrap();
+
+        let err = tx.write(0, PAGESIZE - 2, b"abc").unwrap_err();
+        assert!(matches!(
+            err,
+            BufferError::Page(PageError::OutOfBounds { offset: 14, .. })
+        ));
+        let latest_lsn = tx.log().metrics().latest_lsn;
+        assert_eq!(latest_lsn, 1);
+        tx.rollback().unwrap();
+    }
+}